@@ -0,0 +1,35 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let source_dir = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("luajit2");
+    let header = source_dir.join("src").join("luajit_rolling.h");
+    let relver = source_dir.join(".relver");
+    println!("cargo:rerun-if-changed={}", header.display());
+    println!("cargo:rerun-if-changed={}", relver.display());
+
+    // `LUAJIT_VERSION` is "LuaJIT 2.1.ROLLING" with the placeholder being
+    // substituted by the release number (commit timestamp) at build time.
+    let version = fs::read_to_string(&header)
+        .ok()
+        .and_then(|header| {
+            let line = header.lines().find(|l| {
+                l.starts_with("#define LUAJIT_VERSION\t")
+                    || l.starts_with("#define LUAJIT_VERSION ")
+            })?;
+            let version = line.split('"').nth(1)?;
+            Some(version.trim_start_matches("LuaJIT ").to_string())
+        })
+        .unwrap_or_else(|| "2.1.ROLLING".to_string());
+    let relver = fs::read_to_string(&relver)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()));
+    let version = match relver {
+        Some(relver) => version.replace("ROLLING", &relver),
+        None => version,
+    };
+
+    println!("cargo:rustc-env=LUAJIT_SRC_LUAJIT_VERSION={version}");
+}
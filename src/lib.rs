@@ -1,24 +1,637 @@
+use std::cell::RefCell;
 use std::env;
+use std::error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// Version of the vendored LuaJIT, e.g. `2.1.1713484068`.
+const LUAJIT_VERSION: &str = env!("LUAJIT_SRC_LUAJIT_VERSION");
+
+/// Returns the version of the LuaJIT sources vendored by this crate.
+///
+/// This does not require building LuaJIT.
+pub fn luajit_version() -> &'static str {
+    LUAJIT_VERSION
+}
+
+/// File in the build directory recording what the last unix build was made from.
+const STAMP_FILE: &str = ".luajit-src-stamp";
+
+/// Environment variables (by substring) that may affect the compiled output.
+const STAMP_ENV_VARS: &[&str] = &[
+    "CC",
+    "CFLAGS",
+    "AR",
+    "RANLIB",
+    "STRIP",
+    "LD",
+    "TARGET",
+    "HOST",
+    "DEPLOYMENT",
+    "DEBUG",
+    "OPT_LEVEL",
+];
+
+/// Appended to `luaconf.h` by [`Build::relocatable`].
+const RELOCATABLE_PATHS: &str = r#"
+/* Module paths relative to the executable, added by luajit-src. */
+#ifndef _WIN32
+#undef LUA_PATH_DEFAULT
+#undef LUA_CPATH_DEFAULT
+#define LUA_PATH_DEFAULT \
+  "./?.lua;!/../share/luajit-2.1/?.lua;!/../share/lua/5.1/?.lua;" \
+  "!/../share/lua/5.1/?/init.lua"
+#define LUA_CPATH_DEFAULT \
+  "./?.so;!/../lib/lua/5.1/?.so;!/../lib/lua/5.1/loadall.so"
+#endif
+"#;
+
+/// Prepended to `lib_package.c` by [`Build::relocatable`], replacing the no-op
+/// `setprogdir` of non-Windows targets.
+const RELOCATABLE_PROGDIR: &str = r#"/* Executable directory lookup, added by luajit-src. */
+#ifndef _WIN32
+#include <string.h>
+#if defined(__APPLE__)
+#include <mach-o/dyld.h>
+#elif defined(__linux__)
+#include <unistd.h>
+#endif
+#include "lua.h"
+#include "lauxlib.h"
+
+static void lj_setprogdir(lua_State *L)
+{
+  char buff[4096];
+  char *lb;
+  int ok = 0;
+#if defined(__APPLE__)
+  uint32_t nsize = sizeof(buff);
+  ok = _NSGetExecutablePath(buff, &nsize) == 0;
+#elif defined(__linux__)
+  ssize_t n = readlink("/proc/self/exe", buff, sizeof(buff) - 1);
+  if (n > 0) {
+    buff[n] = '\0';
+    ok = 1;
+  }
+#endif
+  if (ok && (lb = strrchr(buff, '/')) != NULL) {
+    *lb = '\0';
+    luaL_gsub(L, lua_tostring(L, -1), "!", buff);
+    lua_remove(L, -2);  /* remove original string */
+  }
+}
+#endif
+
+"#;
+
+/// Standard libraries opened by `luaL_openlibs`, see [`Build::load_lib`].
+const STD_LIBS: &[&str] = &[
+    "base", "package", "table", "io", "os", "string", "math", "debug", "bit", "jit",
+];
+
+/// x86 target features passed on to the C compiler, see [`Build::x86_features`].
+const X86_FEATURES: &[&str] = &["sse4.1", "sse4.2", "avx", "avx2"];
+
+/// Version script exporting only the public API, see [`Build::version_script`].
+const DEFAULT_VERSION_SCRIPT: &str = "{
+  global:
+    lua_*;
+    luaL_*;
+    luaJIT_*;
+    luaopen_*;
+  local:
+    *;
+};
+";
+
+/// Targets where LuaJIT disables the JIT compiler by default, as the OS forbids
+/// memory that is both writable and executable.
+const WX_TARGETS: &[&str] = &["-ios", "-tvos", "-watchos", "-visionos"];
+
+/// Checks that anonymous memory can be made executable, as the JIT compiler
+/// does with its machine code, see [`Build::runtime_jit_probe`].
+const JIT_PROBE: &str = r#"#include <sys/mman.h>
+int main(void) {
+  void *p = mmap(0, 4096, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANON, -1, 0);
+  if (p == MAP_FAILED) return 1;
+  return mprotect(p, 4096, PROT_READ | PROT_EXEC) != 0;
+}
+"#;
+
+/// Public LuaJIT headers, in dependency order.
+const PUBLIC_HEADERS: &[&str] = &["luaconf.h", "lua.h", "lauxlib.h", "lualib.h", "luajit.h"];
+
+/// Builder for a vendored (or [custom](Build::source_dir)) LuaJIT.
+///
+/// `lua_Number` is always `double`: LuaJIT's value representation (NaN-tagged
+/// doubles), interpreter and JIT backends all assume it, so there is no option
+/// to select `float`. Scripts that need single precision arithmetic can use FFI
+/// `float` values instead.
 pub struct Build {
     out_dir: Option<PathBuf>,
+    source_dir: Option<PathBuf>,
     target: Option<String>,
     host: Option<String>,
     options: Options,
+    on_output: RefCell<Option<OutputCallback>>,
+    warnings: RefCell<Vec<String>>,
 }
 
+type OutputCallback = Box<dyn FnMut(&str)>;
+
+#[derive(Clone, Debug)]
 pub struct Artifacts {
+    target: String,
     include_dir: PathBuf,
     lib_dir: PathBuf,
-    libs: Vec<String>,
+    libs: Vec<Lib>,
+    executable: Option<PathBuf>,
+    test_dir: Option<PathBuf>,
+    link_args: Vec<String>,
+    system_libs: Vec<String>,
+    link_search: Vec<PathBuf>,
+    warnings: Vec<String>,
+    glibc_symbols: Vec<String>,
+    shared_lib: Option<PathBuf>,
+    build_info: BuildInfo,
+    host_tools: Option<HostTools>,
+    compile_commands: Option<PathBuf>,
+    whole_archive: bool,
+    combined_object: Option<PathBuf>,
+    system: bool,
+    timings: Option<Timings>,
+    link_map: Option<PathBuf>,
+    version_script: Option<PathBuf>,
+    object_dir: Option<PathBuf>,
+    jit_dir: Option<PathBuf>,
+    cache_hit: bool,
+    self_check: Option<String>,
+}
+
+/// How a library is linked, see [`Lib`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Static,
+    Dylib,
+}
+
+/// A library to link, see [`Artifacts::libs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lib {
+    name: String,
+    kind: LinkKind,
+}
+
+impl Lib {
+    pub fn new(name: &str, kind: LinkKind) -> Lib {
+        Lib {
+            name: name.to_string(),
+            kind,
+        }
+    }
+
+    /// Returns the name as passed to the linker, e.g. `luajit-5.1`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> LinkKind {
+        self.kind
+    }
+}
+
+/// Host tools produced by [`Build::build_host_tools_only`].
+#[derive(Clone, Debug)]
+pub struct HostTools {
+    minilua: PathBuf,
+    buildvm: PathBuf,
+}
+
+impl HostTools {
+    pub fn minilua(&self) -> &Path {
+        &self.minilua
+    }
+
+    pub fn buildvm(&self) -> &Path {
+        &self.buildvm
+    }
+}
+
+/// Toolchain used for a build, see [`Artifacts::build_info`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct BuildInfo {
+    pub target: String,
+    pub host: String,
+    /// Path to the target C compiler.
+    pub compiler: String,
+    pub compiler_family: CompilerFamily,
+    /// First line of the compiler's `--version` output, if available.
+    pub compiler_version: Option<String>,
+    /// Arguments passed to the compiler on top of `cflags`.
+    pub compiler_args: String,
+    /// Archiver command (`TARGET_AR`), `None` for MSVC.
+    pub ar: Option<String>,
+    /// Strip command (`TARGET_STRIP`), `None` if not stripping or for MSVC.
+    pub strip: Option<String>,
+    /// `XCFLAGS` for unix builds, `CL` flags for MSVC builds.
+    pub cflags: Vec<String>,
+}
+
+impl BuildInfo {
+    fn to_json(&self) -> String {
+        let opt = |v: &Option<String>| v.as_deref().map_or("null".to_string(), json_string);
+        let cflags = self
+            .cflags
+            .iter()
+            .map(|f| json_string(f))
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"target\": {},\n  \"host\": {},\n  \"compiler\": {},\n  \
+             \"compiler_family\": {},\n  \"compiler_version\": {},\n  \"compiler_args\": {},\n  \"ar\": {},\n  \
+             \"strip\": {},\n  \"cflags\": [{}]\n}}\n",
+            json_string(&self.target),
+            json_string(&self.host),
+            json_string(&self.compiler),
+            json_string(self.compiler_family.as_str()),
+            opt(&self.compiler_version),
+            json_string(&self.compiler_args),
+            opt(&self.ar),
+            opt(&self.strip),
+            cflags.join(", "),
+        )
+    }
+}
+
+/// Wall-clock durations of the build phases, see [`Artifacts::timings`].
+#[derive(Clone, Copy, Debug, Default)]
+#[non_exhaustive]
+pub struct Timings {
+    /// Copying the sources into the build directory.
+    pub copy: Duration,
+    /// Building `minilua` and `buildvm`, `None` for MSVC where they are not a
+    /// separate step.
+    pub host_tools: Option<Duration>,
+    /// The main LuaJIT build, including embedded module rebuilds.
+    pub compile: Duration,
+}
+
+impl Timings {
+    fn report(&self) {
+        let host_tools = self.host_tools.map_or(String::new(), |d| {
+            format!(", host tools {:.2}s", d.as_secs_f64())
+        });
+        println!(
+            "cargo:warning=LuaJIT build timings: copy {:.2}s{host_tools}, compile {:.2}s",
+            self.copy.as_secs_f64(),
+            self.compile.as_secs_f64()
+        );
+    }
+}
+
+/// Tools located by [`Build::verify_toolchain`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ToolchainReport {
+    /// Target C compiler.
+    pub compiler: PathBuf,
+    /// Archiver, `None` for MSVC.
+    pub ar: Option<PathBuf>,
+    /// Strip tool, `None` if not stripping or for MSVC.
+    pub strip: Option<PathBuf>,
+    /// `make`, `None` for MSVC.
+    pub make: Option<PathBuf>,
+    /// Host C compiler, only located for cross builds.
+    pub host_compiler: Option<PathBuf>,
+}
+
+/// Family of the C compiler LuaJIT was built with, see [`Artifacts::compiler_family`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompilerFamily {
+    Gnu,
+    Clang,
+    /// MSVC or a compiler with an MSVC-compatible driver such as `clang-cl`.
+    Msvc,
+    Other,
+}
+
+impl CompilerFamily {
+    fn detect(compiler: &cc::Tool) -> CompilerFamily {
+        if compiler.is_like_msvc() {
+            CompilerFamily::Msvc
+        } else if compiler.is_like_clang() {
+            CompilerFamily::Clang
+        } else if compiler.is_like_gnu() {
+            CompilerFamily::Gnu
+        } else {
+            CompilerFamily::Other
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            CompilerFamily::Gnu => "gnu",
+            CompilerFamily::Clang => "clang",
+            CompilerFamily::Msvc => "msvc",
+            CompilerFamily::Other => "other",
+        }
+    }
+}
+
+/// Type of the crate linking LuaJIT, see [`Artifacts::print_cargo_metadata_for`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrateType {
+    Bin,
+    Lib,
+    Rlib,
+    Dylib,
+    Cdylib,
+    Staticlib,
+}
+
+/// Format of the archive written by [`Artifacts::write_archive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A gzip-compressed tarball, created with `tar`.
+    TarGz,
+    /// A zip archive, created with `zip` (`tar` on Windows hosts).
+    Zip,
 }
 
-#[derive(Default, Clone, Copy)]
+/// Error returned by [`Build::try_build`].
+#[derive(Debug)]
+pub enum Error {
+    /// A required setting (such as `TARGET` or `HOST`) is missing.
+    MissingSetting(&'static str),
+    /// A required tool could not be located.
+    ToolNotFound(String),
+    /// A filesystem operation failed.
+    Io { context: String, source: io::Error },
+    /// A build option has an invalid value.
+    InvalidOption(String),
+    /// Artifacts passed to [`Artifacts::merge`] cannot be combined.
+    Incompatible(String),
+    /// The target is not supported by the LuaJIT sources being built.
+    Unsupported(String),
+    /// The build succeeded without producing the expected library.
+    LibraryNotProduced(PathBuf),
+    /// The static library has no symbol index, even after running `ranlib`.
+    MissingArchiveIndex(PathBuf),
+    /// The system LuaJIT is older than [`Build::min_relver`].
+    VersionTooOld { found: String, required: u32 },
+    /// A build command could not be spawned or exited unsuccessfully.
+    CommandFailed {
+        desc: String,
+        command: String,
+        status: Option<ExitStatus>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingSetting(name) => write!(f, "{name} not set"),
+            Error::ToolNotFound(tool) => write!(f, "cannot find {tool}"),
+            Error::InvalidOption(msg) => write!(f, "invalid option: {msg}"),
+            Error::Incompatible(msg) => write!(f, "cannot merge artifacts: {msg}"),
+            Error::Unsupported(msg) => write!(f, "{msg}"),
+            Error::Io { context, source } => write!(f, "{context}: {source}"),
+            Error::LibraryNotProduced(path) => {
+                write!(f, "LuaJIT build did not produce {}", path.display())
+            }
+            Error::MissingArchiveIndex(path) => {
+                write!(f, "{} has no symbol index", path.display())
+            }
+            Error::VersionTooOld { found, required } => {
+                write!(
+                    f,
+                    "system LuaJIT {found} is older than required relver {required}"
+                )
+            }
+            Error::CommandFailed {
+                desc,
+                command,
+                status,
+            } => {
+                write!(f, "\nError {desc}:\n    Command: {command}\n")?;
+                match status {
+                    Some(status) => write!(f, "    Exit status: {status}\n    "),
+                    None => write!(f, "    Command could not be started\n    "),
+                }
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+trait IoResultExt<T> {
+    fn context<F: FnOnce() -> String>(self, f: F) -> Result<T, Error>;
+}
+
+impl<T> IoResultExt<T> for io::Result<T> {
+    fn context<F: FnOnce() -> String>(self, f: F) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            context: f(),
+            source,
+        })
+    }
+}
+
+/// Parallelism used when running the LuaJIT build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jobs {
+    /// Use `NUM_JOBS` if set, otherwise the number of available CPUs.
+    Auto,
+    /// Use exactly this many jobs.
+    Fixed(usize),
+    /// Force a single job (`-j1`).
+    Serial,
+}
+
+impl Jobs {
+    fn count(self) -> usize {
+        self.count_with(env::var("NUM_JOBS").ok().as_deref())
+    }
+
+    // Same as `count`, given the value of `NUM_JOBS`
+    fn count_with(self, num_jobs: Option<&str>) -> usize {
+        match self {
+            Jobs::Auto => num_jobs
+                .and_then(|n| n.parse().ok())
+                .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+                .unwrap_or(1),
+            Jobs::Fixed(n) => n.max(1),
+            Jobs::Serial => 1,
+        }
+    }
+}
+
+/// Operating system LuaJIT is built for, see [`Build::target_os`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TargetOs {
+    Linux,
+    Windows,
+    /// Bare-metal or an otherwise unknown OS. Implies an interpreter-only build
+    /// using the system allocator and without external unwinding.
+    Other,
+}
+
+impl TargetOs {
+    fn target_sys(self) -> &'static str {
+        match self {
+            TargetOs::Linux => "Linux",
+            TargetOs::Windows => "Windows",
+            TargetOs::Other => "Other",
+        }
+    }
+}
+
+/// What to strip from built binaries, see [`Build::strip_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StripMode {
+    /// Strip everything (the makefile default).
+    All,
+    /// Strip debug information only, keeping the symbol table (`strip -S`).
+    DebugOnly,
+    /// Strip local symbols only, keeping debug information (`strip -x`).
+    SymbolsOnly,
+    /// Do not strip.
+    None,
+}
+
+/// Profile-guided optimization step, see [`Build::pgo`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PgoStage {
+    /// Builds instrumented code writing profiles into the directory.
+    Generate(PathBuf),
+    /// Optimizes using the profiles collected in the directory.
+    Use(PathBuf),
+}
+
+/// Memory allocator used by LuaJIT, see [`Build::allocator`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Allocator {
+    /// LuaJIT's bundled allocator.
+    #[default]
+    Internal,
+    /// The system `malloc` (`LUAJIT_USE_SYSMALLOC`).
+    System,
+    /// The system `malloc`, linking `jemalloc` to provide it.
+    Jemalloc,
+    /// The system `malloc`, linking `tcmalloc` to provide it.
+    Tcmalloc,
+}
+
+// A patch added with `Build::patch` or a directory added with `Build::patch_dir`
+#[derive(Clone, Debug)]
+enum Patch {
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+#[derive(Default, Clone, Debug)]
 struct Options {
     lua52compat: bool,
+    jobs: Option<Jobs>,
+    build_tests: bool,
+    relver_file: Option<PathBuf>,
+    auto_metadata: bool,
+    debug_info: bool,
+    assertions: bool,
+    strip: Option<StripMode>,
+    hide_symbols: bool,
+    ranlib: Option<OsString>,
+    embedded_modules: Vec<(String, PathBuf)>,
+    gc64: Option<bool>,
+    disable_ffi_callbacks: bool,
+    shared: bool,
+    default_jit_opt: Option<u8>,
+    host_cc: Option<OsString>,
+    compiler_launcher: Option<String>,
+    host_compiler_launcher: Option<String>,
+    stack_protector: Option<bool>,
+    target_os: Option<TargetOs>,
+    deployment_target: Option<String>,
+    export_dynamic: bool,
+    host_tools_only: bool,
+    link_libucontext: Option<bool>,
+    header_subdir: Option<String>,
+    patches: Vec<Patch>,
+    compile_commands: bool,
+    whole_archive: bool,
+    pointer_width: Option<u8>,
+    lto: bool,
+    allocator: Allocator,
+    msvc_build_script: Option<PathBuf>,
+    msvc_build_args: Option<Vec<String>>,
+    gc_pause: Option<u16>,
+    gc_step_mul: Option<u16>,
+    include_dirs: Vec<PathBuf>,
+    combined_object: bool,
+    keep_objects: bool,
+    relver_from_git: bool,
+    relocatable: bool,
+    cc_args: Vec<String>,
+    hardening: bool,
+    dep_info: Option<PathBuf>,
+    build_script: Option<PathBuf>,
+    dwarf_version: Option<u8>,
+    disable_ffi: bool,
+    disable_jit: Option<bool>,
+    runtime_jit_probe: bool,
+    require_ffi: bool,
+    require_jit: bool,
+    security_prng: Option<bool>,
+    security_strhash: Option<bool>,
+    excluded_libs: Vec<String>,
+    max_page_size: Option<usize>,
+    min_relver: Option<u32>,
+    system_fallback: bool,
+    pie: Option<bool>,
+    require_lib_only: bool,
+    report_timings: bool,
+    neutral_lib_name: bool,
+    hermetic: bool,
+    thin_archive: bool,
+    expect_gc64: Option<bool>,
+    sysroot: Option<PathBuf>,
+    include_out_dir: Option<PathBuf>,
+    lib_out_dir: Option<PathBuf>,
+    fast_math: bool,
+    pgo: Option<PgoStage>,
+    link_map: bool,
+    compiler_target: Option<String>,
+    assert_handler: Option<String>,
+    in_place: bool,
+    x86_features: Option<Vec<String>>,
+    version_script: Option<Option<PathBuf>>,
+    fail_on_warnings: bool,
+    remap_paths: bool,
+    max_glibc_version: Option<String>,
+    self_check: bool,
+    thin_lto: bool,
+    rpaths: Vec<String>,
+    link_search: Vec<PathBuf>,
+    cache_dir: Option<PathBuf>,
 }
 
 impl Build {
@@ -26,9 +639,12 @@ impl Build {
     pub fn new() -> Build {
         Build {
             out_dir: env::var_os("OUT_DIR").map(|s| PathBuf::from(s).join("luajit-build")),
+            source_dir: None,
             target: env::var("TARGET").ok(),
             host: env::var("HOST").ok(),
             options: Options::default(),
+            on_output: RefCell::new(None),
+            warnings: RefCell::new(Vec::new()),
         }
     }
 
@@ -37,6 +653,30 @@ impl Build {
         self
     }
 
+    /// Installs the headers into `path` instead of `out_dir/include`.
+    ///
+    /// Unlike the default location, the directory is not cleared before the build.
+    pub fn include_out_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.include_out_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Installs the libraries into `path` instead of `out_dir/lib`.
+    ///
+    /// Unlike the default location, the directory is not cleared before the build.
+    pub fn lib_out_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.lib_out_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Builds LuaJIT from `path` instead of the vendored sources.
+    ///
+    /// The tree's own `.relver` is used unless [`Build::relver_file`] is set.
+    pub fn source_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.source_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
     pub fn target(&mut self, target: &str) -> &mut Build {
         self.target = Some(target.to_string());
         self
@@ -52,272 +692,4665 @@ impl Build {
         self
     }
 
-    fn cmd_make(&self) -> Command {
-        match &self.host.as_ref().expect("HOST dir not set")[..] {
-            "x86_64-unknown-dragonfly" => Command::new("gmake"),
-            "x86_64-unknown-freebsd" => Command::new("gmake"),
-            _ => Command::new("make"),
-        }
+    /// Shorthand for `debug_info(enabled)`, `assertions(enabled)` and `strip(!enabled)`.
+    pub fn debug(&mut self, enabled: bool) -> &mut Build {
+        self.debug_info(enabled).assertions(enabled).strip(!enabled)
     }
 
-    pub fn build(&mut self) -> Artifacts {
-        let target = &self.target.as_ref().expect("TARGET not set")[..];
+    /// Emits debug information (`-g`, `/Zi` on MSVC).
+    pub fn debug_info(&mut self, enabled: bool) -> &mut Build {
+        self.options.debug_info = enabled;
+        self
+    }
 
-        if target.contains("msvc") {
-            return self.build_msvc();
-        }
+    /// Emits DWARF debug information of the given version (`-gdwarf-{version}`,
+    /// 2-5) when [`Build::debug_info`] is enabled. Not available for MSVC.
+    pub fn dwarf_version(&mut self, version: u8) -> &mut Build {
+        self.options.dwarf_version = Some(version);
+        self
+    }
 
-        self.build_unix()
+    /// Enables LuaJIT internal assertions and Lua C API checks.
+    pub fn assertions(&mut self, enabled: bool) -> &mut Build {
+        self.options.assertions = enabled;
+        self
     }
 
-    pub fn build_unix(&mut self) -> Artifacts {
-        let target = &self.target.as_ref().expect("TARGET not set")[..];
-        let host = &self.host.as_ref().expect("HOST not set")[..];
-        let out_dir = self.out_dir.as_ref().expect("OUT_DIR not set");
-        let source_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("luajit2");
-        let build_dir = out_dir.join("build");
-        let lib_dir = out_dir.join("lib");
-        let include_dir = out_dir.join("include");
+    /// Controls whether built binaries are stripped (enabled by default).
+    ///
+    /// Shorthand for `strip_mode(StripMode::All)` or `strip_mode(StripMode::None)`.
+    pub fn strip(&mut self, enabled: bool) -> &mut Build {
+        self.strip_mode(if enabled {
+            StripMode::All
+        } else {
+            StripMode::None
+        })
+    }
 
-        for dir in &[&build_dir, &lib_dir, &include_dir] {
-            if dir.exists() {
-                fs::remove_dir_all(dir)
-                    .unwrap_or_else(|e| panic!("cannot remove {}: {}", dir.display(), e));
-            }
-            fs::create_dir_all(dir)
-                .unwrap_or_else(|e| panic!("cannot create {}: {}", dir.display(), e));
-        }
-        cp_r(&source_dir, &build_dir);
+    /// Controls what is stripped from built binaries.
+    pub fn strip_mode(&mut self, mode: StripMode) -> &mut Build {
+        self.options.strip = Some(mode);
+        self
+    }
 
-        let mut cc = cc::Build::new();
-        cc.target(target).host(host).warnings(false).opt_level(2);
-        let compiler = cc.get_compiler();
-        let compiler_path = compiler.path().to_str().unwrap();
+    /// Keeps LuaJIT symbols out of the export table of the final linked library.
+    ///
+    /// Uses `--exclude-libs` on ELF targets and an unexported symbols list on Apple.
+    /// Like [`Build::rpath`], this only reaches the package printing the metadata.
+    pub fn hide_symbols(&mut self, enabled: bool) -> &mut Build {
+        self.options.hide_symbols = enabled;
+        self
+    }
 
-        let mut make = self.cmd_make();
-        make.current_dir(build_dir.join("src"));
-        make.arg("-e");
+    /// Runs this `ranlib` on the archive after `make`.
+    ///
+    /// Without it, `RANLIB` or a cross `{prefix}ranlib`/`llvm-ranlib` is used if found.
+    pub fn ranlib<S: AsRef<OsStr>>(&mut self, ranlib: S) -> &mut Build {
+        self.options.ranlib = Some(ranlib.as_ref().to_os_string());
+        self
+    }
 
-        match target {
-            "x86_64-apple-darwin" if env::var_os("MACOSX_DEPLOYMENT_TARGET").is_none() => {
-                make.env("MACOSX_DEPLOYMENT_TARGET", "10.11");
-            }
-            "aarch64-apple-darwin" if env::var_os("MACOSX_DEPLOYMENT_TARGET").is_none() => {
-                make.env("MACOSX_DEPLOYMENT_TARGET", "11.0");
-            }
-            _ if target.contains("linux") => {
-                make.env("TARGET_SYS", "Linux");
-            }
-            _ if target.contains("windows") => {
-                make.env("TARGET_SYS", "Windows");
-            }
-            _ => {}
-        }
+    /// Compiles the Lua module at `source` into the library, registered in
+    /// `package.preload` under `name` by `luaL_openlibs`.
+    ///
+    /// Modules are precompiled to bytecode when the built interpreter can run on
+    /// the host, otherwise they are embedded as source.
+    pub fn embed_lua_module<P: AsRef<Path>>(&mut self, name: &str, source: P) -> &mut Build {
+        let module = (name.to_string(), source.as_ref().to_path_buf());
+        self.options.embedded_modules.push(module);
+        self
+    }
 
-        let target_pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
-        if target_pointer_width == "32" && env::var_os("HOST_CC").is_none() {
-            // 32-bit cross-compilation?
-            let host_cc = cc::Build::new().target(host).get_compiler();
-            make.env("HOST_CC", format!("{} -m32", host_cc.path().display()));
-        }
+    /// Forces GC64 mode on or off. By default LuaJIT enables it on 64-bit targets.
+    pub fn gc64(&mut self, enabled: bool) -> &mut Build {
+        self.options.gc64 = Some(enabled);
+        self
+    }
 
-        // Infer ar/ranlib tools from cross compilers if the it looks like
-        // we're doing something like `foo-gcc` route that to `foo-ranlib`
-        // as well.
-        let prefix = if compiler_path.ends_with("-gcc") {
-            &compiler_path[..compiler_path.len() - 3]
-        } else if compiler_path.ends_with("-clang") {
-            &compiler_path[..compiler_path.len() - 5]
-        } else {
-            ""
-        };
+    /// Fails the build unless the target ends up with a GC64 VM, for bindings
+    /// whose struct layouts depend on it.
+    pub fn require_gc64(&mut self) -> &mut Build {
+        self.options.expect_gc64 = Some(true);
+        self
+    }
 
-        let compiler_path =
-            which::which(compiler_path).unwrap_or_else(|_| panic!("cannot find {compiler_path}"));
-        let bindir = compiler_path.parent().unwrap();
-        let compiler_path = compiler_path.to_str().unwrap();
-        let compiler_args = compiler.cflags_env();
-        let compiler_args = compiler_args.to_str().unwrap();
-        if env::var_os("STATIC_CC").is_none() {
-            make.env("STATIC_CC", format!("{compiler_path} {compiler_args}"));
-        }
-        if env::var_os("TARGET_LD").is_none() {
-            make.env("TARGET_LD", format!("{compiler_path} {compiler_args}"));
-        }
+    /// Fails the build if the target ends up with a GC64 VM.
+    pub fn forbid_gc64(&mut self) -> &mut Build {
+        self.options.expect_gc64 = Some(false);
+        self
+    }
 
-        // Find ar
-        if env::var_os("TARGET_AR").is_none() {
-            let mut ar = if bindir.join(format!("{prefix}ar")).is_file() {
-                bindir.join(format!("{prefix}ar")).into_os_string()
-            } else if compiler.is_like_clang() && bindir.join("llvm-ar").is_file() {
-                bindir.join("llvm-ar").into_os_string()
-            } else if compiler.is_like_gnu() && bindir.join("ar").is_file() {
-                bindir.join("ar").into_os_string()
-            } else if let Ok(ar) = which::which(format!("{prefix}ar")) {
-                ar.into_os_string()
-            } else {
-                panic!("cannot find {prefix}ar")
-            };
-            ar.push(" rcus");
-            make.env("TARGET_AR", ar);
-        }
+    /// Feeds every line printed by the build commands to `callback`.
+    ///
+    /// Lines are still echoed to stdout so they end up in the build log.
+    pub fn on_output<F: FnMut(&str) + 'static>(&mut self, callback: F) -> &mut Build {
+        self.on_output = RefCell::new(Some(Box::new(callback)));
+        self
+    }
 
-        // Find strip
-        if env::var_os("TARGET_STRIP").is_none() {
-            let strip = if bindir.join(format!("{prefix}strip")).is_file() {
-                bindir.join(format!("{prefix}strip"))
-            } else if compiler.is_like_clang() && bindir.join("llvm-strip").is_file() {
-                bindir.join("llvm-strip")
-            } else if compiler.is_like_gnu() && bindir.join("strip").is_file() {
-                bindir.join("strip")
-            } else if let Ok(strip) = which::which(format!("{prefix}strip")) {
-                strip
-            } else {
-                panic!("cannot find {prefix}strip")
-            };
-            make.env("TARGET_STRIP", strip);
-        }
+    /// Builds LuaJIT without the FFI library (`LUAJIT_DISABLE_FFI`).
+    pub fn disable_ffi(&mut self, enabled: bool) -> &mut Build {
+        self.options.disable_ffi = enabled;
+        self
+    }
 
-        let mut xcflags = vec!["-fPIC"];
-        if self.options.lua52compat {
-            xcflags.push("-DLUAJIT_ENABLE_LUA52COMPAT");
-        }
+    /// Builds LuaJIT without the JIT compiler (`LUAJIT_DISABLE_JIT`), leaving
+    /// only the interpreter.
+    ///
+    /// By default the JIT compiler is left out on targets forbidding writable
+    /// and executable memory (iOS, tvOS, watchOS and visionOS) and, with
+    /// [`Build::runtime_jit_probe`], when the build machine forbids it.
+    /// `disable_jit(false)` forces it in everywhere (`LUAJIT_ENABLE_JIT`).
+    pub fn disable_jit(&mut self, enabled: bool) -> &mut Build {
+        self.options.disable_jit = Some(enabled);
+        self
+    }
 
-        make.env("BUILDMODE", "static");
-        make.env("XCFLAGS", xcflags.join(" "));
-        self.run_command(make, "building LuaJIT");
+    /// For native unix builds, checks whether the build machine lets the
+    /// process make memory executable, as the JIT compiler needs, and builds
+    /// without it if not (e.g. under SELinux's `deny_execmem`). Ignored if
+    /// [`Build::disable_jit`] is set.
+    pub fn runtime_jit_probe(&mut self, enabled: bool) -> &mut Build {
+        self.options.runtime_jit_probe = enabled;
+        self
+    }
 
-        for f in &["lauxlib.h", "lua.h", "luaconf.h", "luajit.h", "lualib.h"] {
-            fs::copy(build_dir.join("src").join(f), include_dir.join(f)).unwrap();
-        }
-        fs::copy(
-            build_dir.join("src").join("libluajit.a"),
-            lib_dir.join("libluajit-5.1.a"),
-        )
-        .unwrap();
+    /// Fails the build if the VM would lack the FFI library, e.g. because of
+    /// [`Build::disable_ffi`] or [`Build::sandbox`], for bindings relying on it.
+    pub fn require_ffi(&mut self) -> &mut Build {
+        self.options.require_ffi = true;
+        self
+    }
 
-        Artifacts {
-            lib_dir,
-            include_dir,
-            libs: vec!["luajit-5.1".to_string()],
-        }
+    /// Fails the build if the VM would lack the JIT compiler, see
+    /// [`Build::disable_jit`]. Bare-metal targets never have it.
+    pub fn require_jit(&mut self) -> &mut Build {
+        self.options.require_jit = true;
+        self
     }
 
-    pub fn build_msvc(&mut self) -> Artifacts {
-        let target = &self.target.as_ref().expect("TARGET not set")[..];
-        let out_dir = self.out_dir.as_ref().expect("OUT_DIR not set");
-        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
-        let source_dir = manifest_dir.join("luajit2");
-        let extras_dir = manifest_dir.join("extras");
-        let build_dir = out_dir.join("build");
-        let lib_dir = out_dir.join("lib");
-        let include_dir = out_dir.join("include");
+    /// Controls `LUAJIT_SECURITY_PRNG`, seeding the PRNG used for address space
+    /// randomization from a secure source (on by default in LuaJIT).
+    pub fn security_prng(&mut self, enabled: bool) -> &mut Build {
+        self.options.security_prng = Some(enabled);
+        self
+    }
 
-        for dir in &[&build_dir, &lib_dir, &include_dir] {
-            if dir.exists() {
-                fs::remove_dir_all(dir)
-                    .unwrap_or_else(|e| panic!("cannot remove {}: {}", dir.display(), e));
-            }
-            fs::create_dir_all(dir)
-                .unwrap_or_else(|e| panic!("cannot create {}: {}", dir.display(), e));
-        }
-        cp_r(&source_dir, &build_dir);
+    /// Controls `LUAJIT_SECURITY_STRHASH`, the hash-flooding resistant string
+    /// hash (on by default in LuaJIT).
+    pub fn security_strhash(&mut self, enabled: bool) -> &mut Build {
+        self.options.security_strhash = Some(enabled);
+        self
+    }
 
-        let mut msvcbuild = Command::new(build_dir.join("src").join("msvcbuild.bat"));
-        msvcbuild.current_dir(build_dir.join("src"));
-        if self.options.lua52compat {
-            cp_r(&extras_dir, &build_dir.join("src"));
-            msvcbuild.arg("lua52c");
+    /// Controls whether `luaL_openlibs` opens the standard library `name`
+    /// (`package`, `io`, `os`, ...). All libraries are opened by default.
+    pub fn load_lib(&mut self, name: &str, enabled: bool) -> &mut Build {
+        self.options.excluded_libs.retain(|lib| lib != name);
+        if !enabled {
+            self.options.excluded_libs.push(name.to_string());
         }
-        msvcbuild.arg("static");
+        self
+    }
 
-        let cl = cc::windows_registry::find_tool(target, "cl.exe").expect("failed to find cl");
-        for (k, v) in cl.env() {
-            msvcbuild.env(k, v);
+    /// Preset for running untrusted code: disables the FFI, enables the
+    /// security options and keeps `luaL_openlibs` from opening `package`, `io`
+    /// and `os`.
+    ///
+    /// Each setting can be overridden by calling its own setter afterwards.
+    /// `sandbox(false)` leaves all of them untouched.
+    pub fn sandbox(&mut self, enabled: bool) -> &mut Build {
+        if enabled {
+            self.disable_ffi(true)
+                .security_prng(true)
+                .security_strhash(true);
+            for lib in ["package", "io", "os"] {
+                self.load_lib(lib, false);
+            }
         }
+        self
+    }
 
-        self.run_command(msvcbuild, "building LuaJIT");
+    /// Keeps `luaL_openlibs` from opening the `debug` library, so scripts cannot
+    /// reach `debug.getupvalue`, `debug.sethook` and friends. Same as
+    /// `load_lib("debug", !enabled)`.
+    ///
+    /// Error messages and tracebacks come from the VM and keep their locations.
+    pub fn disable_debug_lib(&mut self, enabled: bool) -> &mut Build {
+        self.load_lib("debug", !enabled)
+    }
 
-        for f in &["lauxlib.h", "lua.h", "luaconf.h", "luajit.h", "lualib.h"] {
-            fs::copy(build_dir.join("src").join(f), include_dir.join(f)).unwrap();
-        }
-        fs::copy(
-            build_dir.join("src").join("lua51.lib"),
-            lib_dir.join("luajit.lib"),
-        )
-        .unwrap();
+    /// Makes FFI callback creation (e.g. `ffi.cast("void(*)()", fn)`) fail, so no
+    /// executable callback trampolines are ever allocated. The rest of the FFI
+    /// is left intact.
+    pub fn disable_ffi_callbacks(&mut self, enabled: bool) -> &mut Build {
+        self.options.disable_ffi_callbacks = enabled;
+        self
+    }
 
-        Artifacts {
-            lib_dir,
-            include_dir,
-            libs: vec!["luajit".to_string()],
-        }
+    /// Builds LuaJIT as a shared library instead of a static one.
+    ///
+    /// On MSVC this produces the classic `lua51.dll` plus its `lua51.lib` import
+    /// library. The shared library has to be shipped alongside the final binary,
+    /// see [`Artifacts::shared_lib`].
+    pub fn shared(&mut self, enabled: bool) -> &mut Build {
+        self.options.shared = enabled;
+        self
     }
 
-    fn run_command(&self, mut command: Command, desc: &str) {
-        println!("running {:?}", command);
-        let status = command.status().unwrap();
-        if !status.success() {
-            panic!(
-                "
-Error {}:
-    Command: {:?}
-    Exit status: {}
-    ",
-                desc, command, status
-            );
-        }
+    /// Sets the JIT optimization level (0-3) used until `jit.opt.start` is called.
+    /// LuaJIT defaults to 3.
+    pub fn default_jit_opt(&mut self, level: u8) -> &mut Build {
+        self.options.default_jit_opt = Some(level);
+        self
     }
-}
 
-fn cp_r(src: &Path, dst: &Path) {
-    for f in fs::read_dir(src).unwrap() {
-        let f = f.unwrap();
-        let path = f.path();
-        let name = path.file_name().unwrap();
+    /// Sets the compiler (including any flags, e.g. `gcc -m32`) used to build the
+    /// host tools (`minilua`, `buildvm`).
+    ///
+    /// Takes precedence over the `HOST_CC` environment variable and the 32-bit
+    /// cross-compilation defaults.
+    pub fn host_cc<S: AsRef<OsStr>>(&mut self, host_cc: S) -> &mut Build {
+        self.options.host_cc = Some(host_cc.as_ref().to_os_string());
+        self
+    }
 
-        // Skip git metadata
-        if name.to_str() == Some(".git") {
-            continue;
-        }
+    /// Runs the target compiler through `launcher` (e.g. `ccache` or
+    /// `sccache`), by prepending it to `STATIC_CC` and `TARGET_LD`. Unix builds
+    /// only.
+    ///
+    /// The host tools are not affected, see [`Build::host_compiler_launcher`].
+    pub fn compiler_launcher(&mut self, launcher: &str) -> &mut Build {
+        self.options.compiler_launcher = Some(launcher.to_string());
+        self
+    }
 
-        let dst = dst.join(name);
-        if f.file_type().unwrap().is_dir() {
-            fs::create_dir_all(&dst).unwrap();
-            cp_r(&path, &dst);
-        } else {
-            let _ = fs::remove_file(&dst);
-            fs::copy(&path, &dst).unwrap();
-        }
+    /// Runs the host compiler building `minilua` and `buildvm` through
+    /// `launcher`, by prepending it to `HOST_CC`. Unix builds only.
+    pub fn host_compiler_launcher(&mut self, launcher: &str) -> &mut Build {
+        self.options.host_compiler_launcher = Some(launcher.to_string());
+        self
     }
-}
 
-impl Artifacts {
-    pub fn include_dir(&self) -> &Path {
-        &self.include_dir
+    /// Forces the compiler's stack protector on or off. Disabling it avoids the
+    /// `__stack_chk_*` symbols some minimal libcs don't provide.
+    pub fn stack_protector(&mut self, enabled: bool) -> &mut Build {
+        self.options.stack_protector = Some(enabled);
+        self
     }
 
-    pub fn lib_dir(&self) -> &Path {
-        &self.lib_dir
+    /// Overrides the OS (`TARGET_SYS`) otherwise derived from the target triple.
+    pub fn target_os(&mut self, os: TargetOs) -> &mut Build {
+        self.options.target_os = Some(os);
+        self
     }
 
-    pub fn libs(&self) -> &[String] {
-        &self.libs
+    /// Sets the minimum OS version for Apple targets, taking precedence over
+    /// `MACOSX_DEPLOYMENT_TARGET` and its iOS/tvOS/watchOS/visionOS counterparts.
+    pub fn deployment_target(&mut self, version: &str) -> &mut Build {
+        self.options.deployment_target = Some(version.to_string());
+        self
     }
 
-    pub fn print_cargo_metadata(&self) {
-        println!("cargo:rerun-if-env-changed=HOST_CC");
-        println!("cargo:rerun-if-env-changed=STATIC_CC");
-        println!("cargo:rerun-if-env-changed=TARGET_LD");
-        println!("cargo:rerun-if-env-changed=TARGET_AR");
-        println!("cargo:rerun-if-env-changed=TARGET_STRIP");
-        println!("cargo:rerun-if-env-changed=MACOSX_DEPLOYMENT_TARGET");
-
-        println!("cargo:rustc-link-search=native={}", self.lib_dir.display());
-        for lib in self.libs.iter() {
-            println!("cargo:rustc-link-lib=static={}", lib);
-        }
-        println!("cargo:include={}", self.include_dir.display());
-        println!("cargo:lib={}", self.lib_dir.display());
+    /// Links the final binary with `-rdynamic` (`-export_dynamic` on Apple) so C
+    /// modules loaded with `require` can resolve the embedded `lua_*` symbols.
+    ///
+    /// Like [`Build::rpath`], this only reaches the package printing the metadata.
+    pub fn export_dynamic(&mut self, enabled: bool) -> &mut Build {
+        self.options.export_dynamic = enabled;
+        self
+    }
+
+    /// Only builds the host tools (`minilua` and `buildvm`) for the configured
+    /// target, installing them into `out_dir/bin`. No library is produced, see
+    /// [`Artifacts::host_tools`].
+    ///
+    /// Not supported for MSVC.
+    pub fn build_host_tools_only(&mut self, enabled: bool) -> &mut Build {
+        self.options.host_tools_only = enabled;
+        self
+    }
+
+    /// Links `libucontext`, which provides `makecontext`/`swapcontext` on musl
+    /// and uClibc.
+    ///
+    /// Vanilla LuaJIT switches coroutine stacks itself and never needs it, so this
+    /// is only enabled by default for musl/uClibc targets when the (patched)
+    /// sources call those functions.
+    pub fn link_libucontext(&mut self, enabled: bool) -> &mut Build {
+        self.options.link_libucontext = Some(enabled);
+        self
+    }
+
+    /// Installs headers under `include/{subdir}` (e.g. `luajit-2.1`) as distros do.
+    /// [`Artifacts::include_dir`] then points at the subdirectory.
+    ///
+    /// The library is always installed with the `-5.1` ABI suffix on unix.
+    pub fn header_subdir(&mut self, subdir: &str) -> &mut Build {
+        self.options.header_subdir = Some(subdir.to_string());
+        self
+    }
+
+    /// Applies a unified diff (`-p1`, relative to the LuaJIT root) to the copied
+    /// sources before building. Patches are applied in the order they are added.
+    ///
+    /// Requires `patch` or `git` to be available.
+    pub fn patch<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        let path = path.as_ref().to_path_buf();
+        self.options.patches.push(Patch::File(path));
+        self
+    }
+
+    /// Applies every `*.patch` and `*.diff` file in `dir` like [`Build::patch`],
+    /// in lexical order or the order listed in a quilt-style `series` file.
+    ///
+    /// `series` entries may be followed by `-pN` to override the strip level,
+    /// other options are rejected.
+    pub fn patch_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Build {
+        let dir = dir.as_ref().to_path_buf();
+        self.options.patches.push(Patch::Dir(dir));
+        self
+    }
+
+    /// Writes a `compile_commands.json` for the LuaJIT sources into the build
+    /// directory, see [`Artifacts::compile_commands`]. Unix builds only.
+    pub fn emit_compile_commands(&mut self, enabled: bool) -> &mut Build {
+        self.options.compile_commands = enabled;
+        self
+    }
+
+    /// Makes [`Artifacts::print_cargo_metadata`] link the static library with
+    /// `+whole-archive`, so no object (and its static initializers) is dropped.
+    ///
+    /// Both forms are available via [`Artifacts::static_libs`] and
+    /// [`Artifacts::whole_archive_libs`] regardless of this setting.
+    pub fn whole_archive(&mut self, enabled: bool) -> &mut Build {
+        self.options.whole_archive = enabled;
+        self
+    }
+
+    /// Sets the target pointer width (32 or 64) instead of reading
+    /// `CARGO_CFG_TARGET_POINTER_WIDTH`, e.g. when building outside a build script.
+    ///
+    /// A 32-bit target makes the host tools build with `-m32`.
+    pub fn pointer_width(&mut self, width: u8) -> &mut Build {
+        self.options.pointer_width = Some(width);
+        self
+    }
+
+    /// Compiles LuaJIT with link-time optimization (`-flto`). Unix builds only.
+    ///
+    /// The archive is created with `gcc-ar` or `llvm-ar` so that the linker can
+    /// use the LTO objects it contains.
+    pub fn lto(&mut self, enabled: bool) -> &mut Build {
+        self.options.lto = enabled;
+        self
+    }
+
+    /// Compiles LuaJIT with Clang's ThinLTO (`-flto=thin`) and archives it with
+    /// `llvm-ar`, so the archive holds LLVM bitcode instead of machine code.
+    /// Implies [`Build::lto`]. Clang builds only.
+    ///
+    /// For cross-language LTO the consuming crate must be built with
+    /// `-Clinker-plugin-lto` and linked by a Clang/LLD whose LLVM version
+    /// matches the one of `rustc` and of the Clang compiling LuaJIT.
+    pub fn thin_lto(&mut self, enabled: bool) -> &mut Build {
+        self.options.thin_lto = enabled;
+        self.options.lto = enabled;
+        self
+    }
+
+    /// Selects the memory allocator used by LuaJIT.
+    ///
+    /// For [`Allocator::Jemalloc`] and [`Allocator::Tcmalloc`] the library is
+    /// linked into the final binary, where it must interpose `malloc`. On 64-bit
+    /// targets any allocator other than [`Allocator::Internal`] requires GC64.
+    pub fn allocator(&mut self, allocator: Allocator) -> &mut Build {
+        self.options.allocator = allocator;
+        self
+    }
+
+    /// Runs this batch file instead of the vendored `msvcbuild.bat` for MSVC
+    /// builds. It is invoked from the copied `src` directory.
+    pub fn msvc_build_script<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.msvc_build_script = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Replaces the arguments passed to the MSVC build script (by default derived
+    /// from options such as [`Build::gc64`], [`Build::lua52compat`] and
+    /// [`Build::shared`]).
+    pub fn msvc_build_args(&mut self, args: &[&str]) -> &mut Build {
+        self.options.msvc_build_args = Some(args.iter().map(|arg| arg.to_string()).collect());
+        self
+    }
+
+    /// Sets the default GC pause in percent (`LUAI_GCPAUSE`, 50-1000, default 200),
+    /// as `collectgarbage("setpause")` would at runtime.
+    pub fn gc_pause(&mut self, percent: u16) -> &mut Build {
+        self.options.gc_pause = Some(percent);
+        self
+    }
+
+    /// Sets the default GC step multiplier in percent (`LUAI_GCMUL`, 100-1000,
+    /// default 200), as `collectgarbage("setstepmul")` would at runtime.
+    pub fn gc_step_mul(&mut self, percent: u16) -> &mut Build {
+        self.options.gc_step_mul = Some(percent);
+        self
+    }
+
+    /// Adds a directory to the include path used to compile LuaJIT, e.g. for
+    /// headers included by a customized `luaconf.h`.
+    pub fn include_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Build {
+        self.options.include_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Additionally partially links (`-r`) the static library into a single
+    /// relocatable `luajit.o`, see [`Artifacts::combined_object`]. Unix builds only.
+    pub fn combined_object(&mut self, enabled: bool) -> &mut Build {
+        self.options.combined_object = enabled;
+        self
+    }
+
+    /// Additionally copies the objects making up the static library to an
+    /// `objects` directory in [`Build::out_dir`], see [`Artifacts::object_dir`].
+    /// Unix builds only.
+    pub fn keep_objects(&mut self, enabled: bool) -> &mut Build {
+        self.options.keep_objects = enabled;
+        self
+    }
+
+    /// Resolves the default `package.path` and `package.cpath` relative to the
+    /// executable (`!/../share/lua/5.1/?.lua` etc.) instead of the absolute
+    /// `/usr/local` prefix, as LuaJIT already does on Windows.
+    ///
+    /// The executable is located via `/proc/self/exe` on Linux and
+    /// `_NSGetExecutablePath` on Apple targets.
+    pub fn relocatable(&mut self, enabled: bool) -> &mut Build {
+        self.options.relocatable = enabled;
+        self
+    }
+
+    /// Appends a raw argument to the target compiler command (`STATIC_CC` and
+    /// `TARGET_LD`, `CL` on MSVC), after all flags the crate adds itself.
+    pub fn cc_arg(&mut self, arg: &str) -> &mut Build {
+        self.options.cc_args.push(arg.to_string());
+        self
+    }
+
+    /// Compiles the C sources with the hardening flags appropriate for the target
+    /// (`-fcf-protection=full` on x86, `-mbranch-protection=standard` on AArch64,
+    /// `-fstack-clash-protection`). Unix builds only.
+    ///
+    /// The hand-written interpreter (`lj_vm.o`) and JIT-compiled code carry no
+    /// CET/BTI markings, so the linker will not enable IBT/BTI enforcement for a
+    /// binary containing LuaJIT.
+    pub fn hardening(&mut self, enabled: bool) -> &mut Build {
+        self.options.hardening = enabled;
+        self
+    }
+
+    /// Aligns ELF segments for pages of up to `size` bytes (`-z max-page-size`),
+    /// both when LuaJIT links itself and in the final binary.
+    ///
+    /// Android targets default to 16 KiB, as required by devices with 16 KiB pages.
+    pub fn max_page_size(&mut self, size: usize) -> &mut Build {
+        self.options.max_page_size = Some(size);
+        self
+    }
+
+    /// Rejects a system LuaJIT in [`Build::resolve`] whose rolling release
+    /// (the last component of `2.1.1713484068`) is older than `relver`.
+    pub fn min_relver(&mut self, relver: u32) -> &mut Build {
+        self.options.min_relver = Some(relver);
+        self
+    }
+
+    /// Makes [`Build::resolve`] build the vendored LuaJIT, with a warning, if no
+    /// suitable system LuaJIT is found.
+    pub fn system_fallback(&mut self, enabled: bool) -> &mut Build {
+        self.options.system_fallback = enabled;
+        self
+    }
+
+    /// Links the `luajit` executable as a position-independent executable
+    /// (`pie(true)`) or not (`pie(false)`). The library itself is always
+    /// compiled with `-fPIC`.
+    ///
+    /// By default no flag is passed and the toolchain's default applies, except
+    /// with musl and the `crt-static` target feature, where the executable is
+    /// linked with `-static-pie` (`-static` with `pie(false)`) like Rust's own
+    /// binaries.
+    pub fn pie(&mut self, enabled: bool) -> &mut Build {
+        self.options.pie = Some(enabled);
+        self
+    }
+
+    /// Tolerates a failing build as long as the library itself was produced,
+    /// for platforms where an optional make target is broken. A warning is
+    /// printed instead of returning an error.
+    pub fn require_lib_only(&mut self, enabled: bool) -> &mut Build {
+        self.options.require_lib_only = enabled;
+        self
+    }
+
+    /// Measures how long copying the sources, building the host tools and the
+    /// main build took and prints them as cargo warnings, see [`Artifacts::timings`].
+    pub fn report_timings(&mut self, enabled: bool) -> &mut Build {
+        self.options.report_timings = enabled;
+        self
+    }
+
+    /// Also installs the library as `libluajit.{a,so,dylib}` (`luajit.lib` for MSVC)
+    /// and reports it in [`Artifacts::libs`] as `luajit`, so consumers can rely
+    /// on one name across platforms.
+    pub fn neutral_lib_name(&mut self, enabled: bool) -> &mut Build {
+        self.options.neutral_lib_name = enabled;
+        self
+    }
+
+    /// Runs `make` in a fresh network namespace (`unshare --net`) so the build
+    /// cannot reach the network. Only supported on Linux hosts allowing
+    /// unprivileged user namespaces, other hosts return an error.
+    pub fn hermetic(&mut self, enabled: bool) -> &mut Build {
+        self.options.hermetic = enabled;
+        self
+    }
+
+    /// Builds `libluajit.a` as a thin archive (`ar T`) which only references the
+    /// object files in the build directory, making links faster.
+    ///
+    /// The installed archive cannot be moved or copied elsewhere and breaks once
+    /// the build directory is cleaned, so this is meant for development builds.
+    /// Not supported for Apple targets and MSVC.
+    pub fn thin_archive(&mut self, enabled: bool) -> &mut Build {
+        self.options.thin_archive = enabled;
+        self
+    }
+
+    /// Passes `--sysroot` to the target compiler. By default GCC is asked for
+    /// its configured sysroot with `-print-sysroot`, which is used if non-empty.
+    pub fn sysroot<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.sysroot = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Compiles LuaJIT's C code with `-ffast-math` (`/fp:fast` for MSVC). The
+    /// assembler VM and JIT-compiled code are unaffected.
+    ///
+    /// **Warning:** this relaxes IEEE 754 semantics in the C library functions,
+    /// e.g. NaN and infinity checks may be optimized away and `string.format`
+    /// or `tonumber` results can differ.
+    pub fn fast_math(&mut self, enabled: bool) -> &mut Build {
+        self.options.fast_math = enabled;
+        self
+    }
+
+    /// Builds LuaJIT's C code for one step of a profile-guided optimization.
+    ///
+    /// With GCC the profiles are used as written. Clang writes raw profiles
+    /// (`luajit-<pid>.profraw`) which must be merged into `luajit.profdata`
+    /// with `llvm-profdata merge` before the [`PgoStage::Use`] build.
+    /// Not supported for MSVC.
+    pub fn pgo(&mut self, stage: PgoStage) -> &mut Build {
+        self.options.pgo = Some(stage);
+        self
+    }
+
+    /// Writes a linker map of the LuaJIT shared library (or, for static builds,
+    /// the `luajit` executable) to `lib_dir/luajit.map`, see [`Artifacts::link_map`].
+    ///
+    /// [`Artifacts::print_cargo_metadata`] also requests a map of the final
+    /// binary at `lib_dir/final.map`. Not supported for MSVC.
+    pub fn emit_link_map(&mut self, enabled: bool) -> &mut Build {
+        self.options.link_map = enabled;
+        self
+    }
+
+    /// Passes `triple` to Clang (or `zig cc`) as the target instead of the one
+    /// derived from the Rust target, for toolchains spelling it differently.
+    ///
+    /// Everything else is still decided based on [`Build::target`].
+    pub fn compiler_target(&mut self, triple: &str) -> &mut Build {
+        self.options.compiler_target = Some(triple.to_string());
+        self
+    }
+
+    /// Calls `function` when a LuaJIT assertion enabled by [`Build::assertions`]
+    /// fails, after the message is printed and before `abort()`.
+    ///
+    /// The function is declared as
+    /// `void function(const char *file, int line, const char *func)` and must be
+    /// defined by the final binary. It should not return (e.g. reset or `longjmp`)
+    /// to avoid the abort.
+    pub fn assert_handler(&mut self, function: &str) -> &mut Build {
+        self.options.assert_handler = Some(function.to_string());
+        self
+    }
+
+    /// Builds in the source tree instead of a fresh copy of it, running
+    /// `make clean` when the configuration changes. If the source tree is not
+    /// writable a single persistent copy in `out_dir` is used instead.
+    ///
+    /// **This leaves build products in the source tree**, including the vendored
+    /// one. Options patching or generating sources cannot be combined with it.
+    /// Not supported for MSVC.
+    pub fn in_place(&mut self, enabled: bool) -> &mut Build {
+        self.options.in_place = enabled;
+        self
+    }
+
+    /// Keeps the outputs of `make` in `dir`, shared between builds and keyed by
+    /// a hash of the configuration, environment, sources and `.relver`. A build
+    /// whose key is cached restores them instead of running `make`, see
+    /// [`Artifacts::cache_hit`].
+    ///
+    /// Cannot be combined with [`Build::in_place`]. Not supported for MSVC.
+    pub fn cache_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Build {
+        self.options.cache_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Compiles LuaJIT's C code for the given x86 target features (`sse4.1`,
+    /// `sse4.2`, `avx` or `avx2`) instead of those enabled for the Rust target in
+    /// `CARGO_CFG_TARGET_FEATURE`. The assembler VM always targets the baseline.
+    pub fn x86_features(&mut self, features: &[&str]) -> &mut Build {
+        self.options.x86_features = Some(features.iter().map(|f| f.to_string()).collect());
+        self
+    }
+
+    /// Links the shared library with `-Wl,--version-script`, using `script` or
+    /// with `None` a built-in one exporting only the `lua_*`, `luaL_*`, `luaJIT_*`
+    /// and `luaopen_*` API, see [`Artifacts::version_script`].
+    ///
+    /// Only supported for shared builds on ELF targets. Rust `cdylib`s already
+    /// restrict their exports with a version script of their own.
+    pub fn version_script(&mut self, script: Option<&Path>) -> &mut Build {
+        self.options.version_script = Some(script.map(Path::to_path_buf));
+        self
+    }
+
+    /// Compiles LuaJIT's C code with `-Wall -Werror` (`/W3 /WX` for MSVC), for
+    /// catching warnings introduced when working on the LuaJIT sources. The host
+    /// tools are not affected.
+    pub fn fail_on_warnings(&mut self, enabled: bool) -> &mut Build {
+        self.options.fail_on_warnings = enabled;
+        self
+    }
+
+    /// Warns about glibc symbol versions newer than `version` (e.g. `"2.17"`)
+    /// referenced by the build, see [`Artifacts::glibc_symbols`].
+    ///
+    /// LuaJIT itself only needs old symbols, but building against newer glibc
+    /// headers binds to newer versions of some: `exp`, `log`, `pow` and friends
+    /// (2.29), `fcntl64` on 32-bit targets (2.28), `memcpy` on x86_64 (2.14) and
+    /// `dlopen`/`dlsym` (2.34, the executable also gets `__libc_start_main`).
+    pub fn max_glibc_version(&mut self, version: &str) -> &mut Build {
+        self.options.max_glibc_version = Some(version.to_string());
+        self
+    }
+
+    /// Runs the built `luajit` interpreter with a trivial script and fails the
+    /// build if it does not work, to catch miscompiles early. Skipped when
+    /// cross-compiling.
+    pub fn self_check(&mut self, enabled: bool) -> &mut Build {
+        self.options.self_check = enabled;
+        self
+    }
+
+    /// Maps the build directory to `luajit` in the debug info (including
+    /// `DW_AT_comp_dir`) and `__FILE__` of the compiled objects, with
+    /// `-ffile-prefix-map`, so they don't depend on where LuaJIT was built.
+    /// GCC or Clang builds only.
+    ///
+    /// Without it only the source file paths in the debug info are made
+    /// relative.
+    pub fn remap_paths(&mut self, enabled: bool) -> &mut Build {
+        self.options.remap_paths = enabled;
+        self
+    }
+
+    /// Adds an rpath to the link arguments printed by
+    /// [`Artifacts::print_cargo_metadata`], so an executable finds the shared
+    /// LuaJIT at runtime. `$ORIGIN` and `@loader_path` are translated to the
+    /// target's equivalent, e.g. `$ORIGIN/lib` becomes `@loader_path/lib` on
+    /// Apple targets.
+    ///
+    /// Only supported for shared builds, and ignored on Windows.
+    ///
+    /// Cargo only applies `cargo:rustc-link-arg` to the binaries, tests and
+    /// examples of the package whose build script prints it, never to
+    /// downstream crates. When building a library such as `mlua-sys`, forward
+    /// [`Artifacts::link_args`] from the final binary's build script instead.
+    pub fn rpath(&mut self, rpath: &str) -> &mut Build {
+        self.options.rpaths.push(rpath.to_string());
+        self
+    }
+
+    /// Adds a directory to the native library search path printed by
+    /// [`Artifacts::print_cargo_metadata`], e.g. for a custom allocator linked
+    /// with [`Build::allocator`] living outside the default search path.
+    pub fn link_search<P: AsRef<Path>>(&mut self, dir: P) -> &mut Build {
+        self.options.link_search.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn jobs(&mut self, jobs: Jobs) -> &mut Build {
+        self.options.jobs = Some(jobs);
+        self
+    }
+
+    /// Also installs the `luajit` interpreter and upstream's test suite (if vendored)
+    /// into `out_dir`, see [`Artifacts::test_runner`].
+    pub fn build_tests(&mut self, enabled: bool) -> &mut Build {
+        self.options.build_tests = enabled;
+        self
+    }
+
+    /// Overrides the file installed as `.relver` in the build tree.
+    pub fn relver_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.relver_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Derives `.relver` from the commit timestamp of a git checkout used as
+    /// [`Build::source_dir`], as upstream's makefile does. Falls back to the tree's
+    /// `.relver` with a warning if git is unavailable.
+    ///
+    /// Ignored if [`Build::relver_file`] is set.
+    pub fn relver_from_git(&mut self, enabled: bool) -> &mut Build {
+        self.options.relver_from_git = enabled;
+        self
+    }
+
+    /// Writes a JSON manifest of the build's inputs (source files, relver and
+    /// effective configuration) to `path` after a successful build, for caching
+    /// in non-Cargo build systems.
+    pub fn write_dep_info<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.dep_info = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Writes a script to `path` that reruns the `make` (or `msvcbuild.bat`)
+    /// invocation of the build with all the environment variables and arguments
+    /// it was given, for rebuilding LuaJIT without Cargo. The script is a batch
+    /// file on Windows hosts and a POSIX shell script elsewhere.
+    ///
+    /// The script runs in the build directory under [`Build::out_dir`] and is
+    /// written before the build starts, so it is kept if the build fails.
+    pub fn write_build_script<P: AsRef<Path>>(&mut self, path: P) -> &mut Build {
+        self.options.build_script = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Calls [`Artifacts::print_cargo_metadata`] after a successful build
+    /// when running inside a build script.
+    pub fn auto_metadata(&mut self, enabled: bool) -> &mut Build {
+        self.options.auto_metadata = enabled;
+        self
+    }
+
+    // Outside of build scripts there is no `OUT_DIR`, so fall back to a
+    // per-target directory under `CARGO_TARGET_DIR` or the system temp dir.
+    fn resolved_out_dir(&self, target: &str) -> PathBuf {
+        match &self.out_dir {
+            Some(out_dir) => out_dir.clone(),
+            None => {
+                let target_dir = env::var_os("CARGO_TARGET_DIR").map(PathBuf::from);
+                default_out_dir(target_dir, target)
+            }
+        }
+    }
+
+    fn resolved_source_dir(&self) -> PathBuf {
+        match &self.source_dir {
+            Some(dir) => dir.clone(),
+            None => Path::new(env!("CARGO_MANIFEST_DIR")).join("luajit2"),
+        }
+    }
+
+    // Creates the build, lib and include directories under `out_dir`, wiping any
+    // previous contents if `clean` is set
+    fn prepare_dirs(
+        &self,
+        out_dir: &Path,
+        clean: bool,
+    ) -> Result<(PathBuf, PathBuf, PathBuf), Error> {
+        let build_dir = out_dir.join("build");
+        let lib_dir = self
+            .options
+            .lib_out_dir
+            .clone()
+            .unwrap_or_else(|| out_dir.join("lib"));
+        let include_root = self
+            .options
+            .include_out_dir
+            .clone()
+            .unwrap_or_else(|| out_dir.join("include"));
+
+        // Directories given by the user may hold other files, leave them be
+        for (dir, owned) in [
+            (&build_dir, true),
+            (&lib_dir, self.options.lib_out_dir.is_none()),
+            (&include_root, self.options.include_out_dir.is_none()),
+        ] {
+            if clean && owned && dir.exists() {
+                fs::remove_dir_all(dir).context(|| format!("cannot remove {}", dir.display()))?;
+            }
+            fs::create_dir_all(dir).context(|| format!("cannot create {}", dir.display()))?;
+        }
+
+        let include_dir = match &self.options.header_subdir {
+            Some(subdir) => {
+                let include_dir = include_root.join(subdir);
+                fs::create_dir_all(&include_dir)
+                    .context(|| format!("cannot create {}", include_dir.display()))?;
+                include_dir
+            }
+            None => include_root,
+        };
+
+        Ok((build_dir, lib_dir, include_dir))
+    }
+
+    // Configuration and source file metadata a unix build was made from, with a
+    // blank line between the two
+    fn source_stamp(&self, source_dir: &Path) -> Result<String, Error> {
+        let mut stamp = format!("{:?} {:?} {:?}\n", self.target, self.host, self.options);
+        let mut vars = env::vars_os()
+            .filter(|(name, _)| {
+                let name = name.to_string_lossy();
+                STAMP_ENV_VARS.iter().any(|var| name.contains(var))
+            })
+            .collect::<Vec<_>>();
+        vars.sort();
+        for (name, value) in vars {
+            stamp.push_str(&format!("{name:?}={value:?}\n"));
+        }
+        if let Some(relver_file) = &self.options.relver_file {
+            stamp.push_str(&file_stamp(relver_file, relver_file)?);
+        }
+        stamp.push('\n');
+
+        let mut files = Vec::new();
+        list_files(source_dir, Path::new(""), &mut files)?;
+        files.sort();
+        for file in files {
+            stamp.push_str(&file_stamp(&source_dir.join(&file), &file)?);
+        }
+        Ok(stamp)
+    }
+
+    // Source headers changed since the build recorded in `build_dir`, or `None`
+    // if anything else changed and a full rebuild is needed
+    fn changed_headers(&self, build_dir: &Path, stamp: &str) -> Option<Vec<PathBuf>> {
+        // Patched and generated files cannot simply be copied over again, and
+        // make does not track headers outside the LuaJIT tree or the git state
+        if !self.options.patches.is_empty()
+            || !self.options.embedded_modules.is_empty()
+            || !self.options.include_dirs.is_empty()
+            || self.options.relver_from_git
+        {
+            return None;
+        }
+        let previous = fs::read_to_string(build_dir.join(STAMP_FILE)).ok()?;
+        let (previous_config, previous_files) = previous.split_once("\n\n")?;
+        let (config, files) = stamp.split_once("\n\n")?;
+        if previous_config != config || previous_files.lines().count() != files.lines().count() {
+            return None;
+        }
+
+        let mut changed = Vec::new();
+        for (previous, current) in previous_files.lines().zip(files.lines()) {
+            if previous == current {
+                continue;
+            }
+            let path = current.split('\t').next()?;
+            if previous.split('\t').next() != Some(path) || !path.ends_with(".h") {
+                return None;
+            }
+            if self.patches_file(path) {
+                return None;
+            }
+            changed.push(PathBuf::from(path));
+        }
+        Some(changed)
+    }
+
+    // Picks the tree for an `in_place` build: the source tree itself if writable,
+    // otherwise a copy in `out_dir` that is only refreshed when the sources
+    // change. Also returns whether objects of another configuration must be
+    // removed with `make clean` first.
+    fn prepare_in_place(
+        &self,
+        out_dir: &Path,
+        source_dir: &Path,
+        stamp: &str,
+    ) -> Result<(PathBuf, PathBuf, PathBuf, bool), Error> {
+        if self.modifies_sources() {
+            return Err(Error::InvalidOption(
+                "in_place builds cannot patch or generate sources".to_string(),
+            ));
+        }
+        let probe = source_dir.join("src").join(".luajit-src-writable");
+        let writable = fs::write(&probe, "")
+            .and_then(|_| fs::remove_file(&probe))
+            .is_ok();
+        let build_dir = if writable {
+            source_dir.to_path_buf()
+        } else {
+            out_dir.join("build")
+        };
+
+        let (config, files) = stamp.split_once("\n\n").unwrap();
+        let previous = fs::read_to_string(build_dir.join(STAMP_FILE)).ok();
+        let previous = previous.as_deref().and_then(|s| s.split_once("\n\n"));
+        let recopy = !writable && previous.is_none_or(|(_, previous)| previous != files);
+        let (_, lib_dir, include_dir) = self.prepare_dirs(out_dir, writable || recopy)?;
+        if recopy {
+            self.copy_sources(source_dir, &build_dir)?;
+        }
+        let make_clean = !recopy && previous.is_none_or(|(previous, _)| previous != config);
+        Ok((build_dir, lib_dir, include_dir, make_clean))
+    }
+
+    // Whether the build changes or adds files in the LuaJIT tree
+    fn modifies_sources(&self) -> bool {
+        let options = &self.options;
+        !options.patches.is_empty()
+            || !options.embedded_modules.is_empty()
+            || !options.excluded_libs.is_empty()
+            || options.relver_file.is_some()
+            || options.relver_from_git
+            || options.disable_ffi_callbacks
+            || options.default_jit_opt.is_some()
+            || options.gc_pause.is_some()
+            || options.gc_step_mul.is_some()
+            || options.relocatable
+            || options.assert_handler.is_some()
+    }
+
+    fn copy_sources(&self, source_dir: &Path, build_dir: &Path) -> Result<(), Error> {
+        cp_r(source_dir, build_dir)?;
+        if let Some(relver_file) = &self.options.relver_file {
+            copy_file(relver_file, &build_dir.join(".relver"))
+                .context(|| format!("cannot copy {}", relver_file.display()))?;
+        } else if self.options.relver_from_git {
+            let output = Command::new("git")
+                .args(["show", "-s", "--format=%ct", "HEAD"])
+                .current_dir(source_dir)
+                .output();
+            match output {
+                Ok(output) if output.status.success() => {
+                    fs::write(build_dir.join(".relver"), &output.stdout)
+                        .context(|| "cannot write .relver".to_string())?;
+                }
+                _ => self.warn(format!(
+                    "cannot get relver from git in {}, using .relver",
+                    source_dir.display()
+                )),
+            }
+        }
+        self.apply_patches(build_dir)?;
+        self.patch_sources(&build_dir.join("src"))
+    }
+
+    // Patch files in the order they are applied, with `patch_dir` directories
+    // expanded, and the `-p` strip level of each
+    fn resolved_patches(&self) -> Result<Vec<(PathBuf, u32)>, Error> {
+        let cwd = env::current_dir().context(|| "cannot get current directory".to_string())?;
+        let mut patches = Vec::new();
+        for patch in &self.options.patches {
+            let path = match patch {
+                Patch::File(path) => {
+                    let path = cwd.join(path);
+                    if path.is_dir() {
+                        let msg = format!("{} is a directory, use patch_dir", path.display());
+                        return Err(Error::InvalidOption(msg));
+                    }
+                    patches.push((path, 1));
+                    continue;
+                }
+                Patch::Dir(path) => cwd.join(path),
+            };
+            if !path.is_dir() {
+                let msg = format!("{} is not a directory", path.display());
+                return Err(Error::InvalidOption(msg));
+            }
+            let series = path.join("series");
+            if series.is_file() {
+                let contents = fs::read_to_string(&series)
+                    .context(|| format!("cannot read {}", series.display()))?;
+                for line in contents.lines() {
+                    let mut words = line.split_whitespace();
+                    let Some(name) = words.next().filter(|name| !name.starts_with('#')) else {
+                        continue;
+                    };
+                    let mut strip = 1;
+                    for option in words.take_while(|word| !word.starts_with('#')) {
+                        match option.strip_prefix("-p").and_then(|n| n.parse().ok()) {
+                            Some(n) => strip = n,
+                            None => {
+                                let msg = format!(
+                                    "unsupported option `{option}` for {name} in {}",
+                                    series.display()
+                                );
+                                return Err(Error::InvalidOption(msg));
+                            }
+                        }
+                    }
+                    patches.push((path.join(name), strip));
+                }
+                continue;
+            }
+            let mut files = fs::read_dir(&path)
+                .context(|| format!("cannot read {}", path.display()))?
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|file| {
+                    file.extension()
+                        .is_some_and(|ext| ext == "patch" || ext == "diff")
+                })
+                .collect::<Vec<_>>();
+            files.sort();
+            patches.extend(files.into_iter().map(|file| (file, 1)));
+        }
+        Ok(patches)
+    }
+
+    fn apply_patches(&self, build_dir: &Path) -> Result<(), Error> {
+        if self.options.patches.is_empty() {
+            return Ok(());
+        }
+        let use_patch = which::which("patch").is_ok();
+        for (patch, strip) in self.resolved_patches()? {
+            let strip = format!("-p{strip}");
+            let mut cmd = if use_patch {
+                let mut cmd = Command::new("patch");
+                cmd.args([&strip, "--batch", "--forward", "-i"]).arg(&patch);
+                cmd
+            } else {
+                let mut cmd = Command::new("git");
+                cmd.args(["apply", &strip, "--verbose"]).arg(&patch);
+                cmd
+            };
+            cmd.current_dir(build_dir);
+            println!("running {:?}", cmd);
+            let output = cmd.output().ok();
+            if let Some(output) = output.as_ref().filter(|o| o.status.success()) {
+                io::Write::write_all(&mut io::stdout(), &output.stdout).ok();
+                continue;
+            }
+            // Report the hunks that did not apply
+            let mut desc = format!("applying patch {}", patch.display());
+            if let Some(output) = &output {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                for line in stdout.lines().chain(stderr.lines()) {
+                    if line.contains("FAILED") || line.starts_with("error:") {
+                        desc.push_str("\n    ");
+                        desc.push_str(line.trim());
+                    }
+                }
+            }
+            return Err(Error::CommandFailed {
+                desc,
+                command: format!("{cmd:?}"),
+                status: output.map(|o| o.status),
+            });
+        }
+        Ok(())
+    }
+
+    // Whether `patch_sources` modifies the source file at `path`
+    fn patches_file(&self, path: &str) -> bool {
+        (path.ends_with("lj_jit.h") && self.options.default_jit_opt.is_some())
+            || (path.ends_with("luaconf.h")
+                && (self.options.gc_pause.is_some()
+                    || self.options.gc_step_mul.is_some()
+                    || self.options.relocatable))
+    }
+
+    // Source-level tweaks that have no corresponding LuaJIT define
+    fn patch_sources(&self, src_dir: &Path) -> Result<(), Error> {
+        if self.options.disable_ffi_callbacks {
+            // Returning NULL makes the conversion to a function pointer fail
+            patch_source(
+                src_dir,
+                "lj_ccallback.c",
+                "void *lj_ccallback_new(CTState *cts, CType *ct, GCfunc *fn)\n{\n",
+                "void *lj_ccallback_new(CTState *cts, CType *ct, GCfunc *fn)\n{\n  \
+                 if (cts) return NULL;  /* Callbacks disabled by luajit-src. */\n",
+            )?;
+        }
+        if let Some(level) = self.options.default_jit_opt {
+            let value = format!("JIT_F_OPT_{level}");
+            patch_define(src_dir, "lj_jit.h", "JIT_F_OPT_DEFAULT", &value)?;
+        }
+        // luaconf.h defines these unconditionally, so `-D` would not override them
+        if let Some(pause) = self.options.gc_pause {
+            patch_define(src_dir, "luaconf.h", "LUAI_GCPAUSE", &pause.to_string())?;
+        }
+        if let Some(step_mul) = self.options.gc_step_mul {
+            patch_define(src_dir, "luaconf.h", "LUAI_GCMUL", &step_mul.to_string())?;
+        }
+        if !self.options.excluded_libs.is_empty() {
+            // Drop the entries from the `lj_lib_load` table used by luaL_openlibs
+            let path = src_dir.join("lib_init.c");
+            let contents =
+                fs::read_to_string(&path).context(|| "cannot read lib_init.c".to_string())?;
+            let mut lines = contents.lines().collect::<Vec<_>>();
+            for lib in &self.options.excluded_libs {
+                let open = format!("luaopen_{lib}");
+                let count = lines.len();
+                lines.retain(|line| {
+                    let entry = line.trim_start().starts_with('{');
+                    !(entry
+                        && line
+                            .split(|c: char| !c.is_alphanumeric() && c != '_')
+                            .any(|w| w == open))
+                });
+                if lines.len() == count {
+                    return Err(Error::Unsupported(format!(
+                        "cannot patch lib_init.c: `{open}` not found in LuaJIT sources"
+                    )));
+                }
+            }
+            fs::write(&path, lines.join("\n") + "\n")
+                .context(|| "cannot write lib_init.c".to_string())?;
+        }
+        if let Some(handler) = &self.options.assert_handler {
+            patch_source(
+                src_dir,
+                "lj_assert.c",
+                "void lj_assert_fail(",
+                &format!(
+                    "extern void {handler}(const char *file, int line, const char *func);\n\n\
+                     void lj_assert_fail("
+                ),
+            )?;
+            patch_source(
+                src_dir,
+                "lj_assert.c",
+                "  abort();",
+                &format!("  {handler}(file, line, func);  /* Added by luajit-src. */\n  abort();"),
+            )?;
+        }
+        if self.options.relocatable {
+            // Windows already substitutes `!` via its own setprogdir()
+            patch_define(
+                src_dir,
+                "lib_package.c",
+                "setprogdir(L)",
+                "lj_setprogdir(L)",
+            )?;
+            let path = src_dir.join("lib_package.c");
+            let contents =
+                fs::read_to_string(&path).context(|| "cannot read lib_package.c".to_string())?;
+            fs::write(&path, format!("{RELOCATABLE_PROGDIR}{contents}"))
+                .context(|| "cannot write lib_package.c".to_string())?;
+
+            let path = src_dir.join("luaconf.h");
+            let mut contents =
+                fs::read_to_string(&path).context(|| "cannot read luaconf.h".to_string())?;
+            contents.push_str(RELOCATABLE_PATHS);
+            fs::write(&path, contents).context(|| "cannot write luaconf.h".to_string())?;
+        }
+        Ok(())
+    }
+
+    fn cmd_make(&self) -> Result<Command, Error> {
+        let host = self.host.as_ref().ok_or(Error::MissingSetting("HOST"))?;
+        let make = make_program(host);
+        if !self.options.hermetic {
+            return Ok(Command::new(make));
+        }
+
+        let unshare = ["--net", "--map-root-user"];
+        let isolated = host.contains("linux")
+            && Command::new("unshare")
+                .args(unshare)
+                .arg("true")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success());
+        if !isolated {
+            return Err(Error::Unsupported(
+                "hermetic builds require `unshare --net --map-root-user` on a Linux host"
+                    .to_string(),
+            ));
+        }
+        let mut command = Command::new("unshare");
+        command.args(unshare).arg(make);
+        Ok(command)
+    }
+
+    /// Locates every tool the build needs without building anything.
+    ///
+    /// Unlike the build itself, which stops at the first missing tool, the
+    /// returned [`Error::ToolNotFound`] lists all of them. Tools given through
+    /// `TARGET_AR` and `TARGET_STRIP` are reported as-is.
+    pub fn verify_toolchain(&self) -> Result<ToolchainReport, Error> {
+        let target = &self
+            .target
+            .as_ref()
+            .ok_or(Error::MissingSetting("TARGET"))?[..];
+        let host = &self.host.as_ref().ok_or(Error::MissingSetting("HOST"))?[..];
+        if target.contains("msvc") {
+            let cl = cc::windows_registry::find_tool(target, "cl.exe")
+                .ok_or_else(|| Error::ToolNotFound("cl.exe".to_string()))?;
+            return Ok(ToolchainReport {
+                compiler: cl.path().to_path_buf(),
+                ar: None,
+                strip: None,
+                make: None,
+                host_compiler: None,
+            });
+        }
+
+        let mut missing = Vec::new();
+        let mut found = |result: Result<PathBuf, Error>| match result {
+            Ok(path) => Ok(Some(path)),
+            Err(Error::ToolNotFound(tool)) => {
+                missing.push(tool);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        };
+        let which =
+            |name: &str| which::which(name).map_err(|_| Error::ToolNotFound(name.to_string()));
+
+        let compiler = cc::Build::new()
+            .target(target)
+            .host(host)
+            .warnings(false)
+            .opt_level(2)
+            .get_compiler();
+        let resolved = found(which(compiler.path().to_str().unwrap()))?;
+        let compiler_path = resolved
+            .clone()
+            .unwrap_or_else(|| compiler.path().to_path_buf());
+        let compiler_path_str = compiler_path.to_str().unwrap();
+        let is_zig = is_zig(&compiler);
+
+        let ar = match env::var_os("TARGET_AR") {
+            Some(ar) => Some(PathBuf::from(ar)),
+            None => found(
+                self.find_ar(&compiler, compiler_path_str, is_zig)
+                    .map(PathBuf::from),
+            )?,
+        };
+        let strip = if self.options.strip == Some(StripMode::None) {
+            None
+        } else if let Some(strip) = env::var_os("TARGET_STRIP") {
+            Some(PathBuf::from(strip))
+        } else if is_zig && env::var_os("STRIP").is_none() {
+            None
+        } else {
+            found(self.find_strip(&compiler, compiler_path_str))?
+        };
+        let make = found(which(make_program(host)))?;
+        let host_compiler = if target != host {
+            let host_cc = match self
+                .options
+                .host_cc
+                .clone()
+                .or_else(|| env::var_os("HOST_CC"))
+            {
+                Some(host_cc) => host_cc.to_string_lossy().into_owned(),
+                None => cc::Build::new()
+                    .target(host)
+                    .host(host)
+                    .opt_level(2)
+                    .get_compiler()
+                    .path()
+                    .display()
+                    .to_string(),
+            };
+            // `HOST_CC` may carry flags such as `-m32`
+            let name = host_cc.split_whitespace().next().unwrap_or_default();
+            found(which(name))?
+        } else {
+            None
+        };
+        let launchers = [
+            &self.options.compiler_launcher,
+            &self.options.host_compiler_launcher,
+        ];
+        for launcher in launchers.into_iter().flatten() {
+            let name = launcher.split_whitespace().next().unwrap_or_default();
+            found(which(name))?;
+        }
+
+        if !missing.is_empty() {
+            return Err(Error::ToolNotFound(missing.join(", ")));
+        }
+        Ok(ToolchainReport {
+            compiler: compiler_path,
+            ar,
+            strip,
+            make,
+            host_compiler,
+        })
+    }
+
+    pub fn build(&mut self) -> Artifacts {
+        match self.try_build() {
+            Ok(artifacts) => artifacts,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Like [`Build::build`], but returns an error instead of panicking.
+    pub fn try_build(&mut self) -> Result<Artifacts, Error> {
+        let target = self.target.clone().ok_or(Error::MissingSetting("TARGET"))?;
+        self.validate()?;
+        self.warnings.take();
+        let wx_target = WX_TARGETS.iter().any(|os| target.contains(os));
+        match self.options.disable_jit {
+            None if wx_target => self.warn(format!(
+                "building LuaJIT without the JIT compiler, {target} forbids executable memory"
+            )),
+            Some(false) if wx_target => self.warn(format!(
+                "the JIT compiler is forced on, but {target} forbids executable memory"
+            )),
+            _ => {}
+        }
+        if self.out_dir.is_none() {
+            let out_dir = self.resolved_out_dir(&target);
+            self.warn(format!(
+                "OUT_DIR is not set, building LuaJIT in {}",
+                out_dir.display()
+            ));
+        }
+        if self.options.gc64 == Some(false) && target.starts_with("x86_64") {
+            self.warn(
+                "disabling GC64 limits the VM to the lower 2GB of the address space and is \
+                 discouraged upstream"
+                    .to_string(),
+            );
+        }
+
+        let mut artifacts = if target.contains("msvc") {
+            self.try_build_msvc()?
+        } else {
+            self.try_build_unix()?
+        };
+        artifacts.link_args = self.link_args(&target, &artifacts)?;
+        artifacts.system_libs = self.system_libs(&target)?;
+        artifacts.link_search = self.options.link_search.clone();
+        artifacts.warnings = self.warnings.take();
+        if let Some(path) = &self.options.dep_info {
+            self.write_dep_info_to(path, &target, &artifacts)?;
+        }
+
+        // Only meaningful when Cargo is reading our stdout
+        let in_build_script =
+            env::var_os("OUT_DIR").is_some() && env::var_os("CARGO_CFG_TARGET_OS").is_some();
+        if self.options.auto_metadata && in_build_script {
+            artifacts.print_cargo_metadata();
+        }
+
+        Ok(artifacts)
+    }
+
+    /// Uses a system LuaJIT found with `pkg-config` if `LUAJIT_SYS_USE_PKG_CONFIG=1`
+    /// is set, otherwise builds the vendored LuaJIT like [`Build::try_build`].
+    ///
+    /// Using the system LuaJIT is reported with a `cargo:warning`. Probing
+    /// failures are reported as errors unless [`Build::system_fallback`] is
+    /// enabled.
+    pub fn resolve(&mut self) -> Result<Artifacts, Error> {
+        println!("cargo:rerun-if-env-changed=LUAJIT_SYS_USE_PKG_CONFIG");
+        self.warnings.take();
+        let use_system = env::var("LUAJIT_SYS_USE_PKG_CONFIG").as_deref() == Ok("1");
+        let probed = if use_system {
+            match self.probe_system() {
+                Ok(artifacts) => Some(artifacts),
+                Err(err) if self.options.system_fallback => {
+                    self.warn(format!("{err}, falling back to vendored LuaJIT"));
+                    None
+                }
+                Err(err) => return Err(err),
+            }
+        } else {
+            None
+        };
+        let Some(mut artifacts) = probed else {
+            // `try_build` starts over with its own warnings
+            let warnings = self.warnings.take();
+            let mut artifacts = self.try_build()?;
+            artifacts.warnings.splice(0..0, warnings);
+            // The default, so only noted in the build script output
+            println!("using vendored LuaJIT {LUAJIT_VERSION}");
+            return Ok(artifacts);
+        };
+
+        self.warn(format!(
+            "using system LuaJIT from {}",
+            artifacts.lib_dir.display()
+        ));
+        artifacts.warnings = self.warnings.take();
+        let in_build_script =
+            env::var_os("OUT_DIR").is_some() && env::var_os("CARGO_CFG_TARGET_OS").is_some();
+        if self.options.auto_metadata && in_build_script {
+            artifacts.print_cargo_metadata();
+        }
+        Ok(artifacts)
+    }
+
+    fn write_dep_info_to(
+        &self,
+        path: &Path,
+        target: &str,
+        artifacts: &Artifacts,
+    ) -> Result<(), Error> {
+        let source_dir = self.resolved_source_dir();
+        let mut inputs = Vec::new();
+        list_files(&source_dir, Path::new(""), &mut inputs)?;
+        inputs.sort();
+        let mut inputs = inputs
+            .into_iter()
+            .map(|file| source_dir.join(file))
+            .collect::<Vec<_>>();
+        inputs.extend(self.options.relver_file.iter().cloned());
+        inputs.extend(self.resolved_patches()?.into_iter().map(|(patch, _)| patch));
+        inputs.extend(
+            self.options
+                .embedded_modules
+                .iter()
+                .map(|(_, path)| path.clone()),
+        );
+
+        let relver_path = self.resolved_out_dir(target).join("build").join(".relver");
+        let relver = fs::read_to_string(&relver_path).unwrap_or_default();
+        let inputs = inputs
+            .iter()
+            .map(|input| format!("    {}", json_string(&input.display().to_string())))
+            .collect::<Vec<_>>();
+        let json = format!(
+            "{{\n  \"relver\": {},\n  \"inputs\": [\n{}\n  ],\n  \"options\": {},\n  \
+             \"build_info\": {}\n}}\n",
+            json_string(relver.trim()),
+            inputs.join(",\n"),
+            json_string(&format!("{:?}", self.options)),
+            artifacts
+                .build_info
+                .to_json()
+                .trim_end()
+                .replace('\n', "\n  "),
+        );
+        fs::write(path, json).context(|| format!("cannot write {}", path.display()))
+    }
+
+    fn probe_system(&self) -> Result<Artifacts, Error> {
+        let target = self
+            .target
+            .as_ref()
+            .ok_or(Error::MissingSetting("TARGET"))?;
+        let pkg_config = env::var_os("PKG_CONFIG").unwrap_or_else(|| "pkg-config".into());
+        let query = |args: &[&str]| {
+            let mut cmd = Command::new(&pkg_config);
+            cmd.args(args).arg("luajit");
+            match cmd.output() {
+                Ok(output) if output.status.success() => {
+                    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+                }
+                output => Err(Error::CommandFailed {
+                    desc: "probing system LuaJIT".to_string(),
+                    command: format!("{cmd:?}"),
+                    status: output.ok().map(|output| output.status),
+                }),
+            }
+        };
+
+        if let Some(required) = self.options.min_relver {
+            check_relver(query(&["--modversion"])?, required)?;
+        }
+
+        let include_dir = PathBuf::from(query(&["--variable=includedir"])?);
+        let lib_dir = PathBuf::from(query(&["--variable=libdir"])?);
+        let libs = query(&["--libs-only-l"])?
+            .split_whitespace()
+            .filter_map(|lib| lib.strip_prefix("-l"))
+            .map(|lib| Lib::new(lib, LinkKind::Dylib))
+            .collect();
+
+        Ok(Artifacts {
+            target: target.clone(),
+            include_dir,
+            lib_dir,
+            libs,
+            executable: None,
+            test_dir: None,
+            link_args: Vec::new(),
+            system_libs: Vec::new(),
+            link_search: Vec::new(),
+            warnings: Vec::new(),
+            glibc_symbols: Vec::new(),
+            shared_lib: None,
+            build_info: BuildInfo {
+                target: target.clone(),
+                host: self.host.clone().unwrap_or_default(),
+                compiler: String::new(),
+                compiler_family: CompilerFamily::Other,
+                compiler_version: None,
+                compiler_args: String::new(),
+                ar: None,
+                strip: None,
+                cflags: Vec::new(),
+            },
+            host_tools: None,
+            compile_commands: None,
+            whole_archive: false,
+            combined_object: None,
+            system: true,
+            timings: None,
+            link_map: None,
+            version_script: None,
+            object_dir: None,
+            jit_dir: None,
+            cache_hit: false,
+            self_check: None,
+        })
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let is_64bit = match self.options.pointer_width {
+            Some(width) => width == 64,
+            None => env::var("CARGO_CFG_TARGET_POINTER_WIDTH").is_ok_and(|width| width == "64"),
+        };
+        if let Some(expected) = self.options.expect_gc64 {
+            // GC64 is the only mode of the non-x86 64-bit ports and can only be
+            // switched off on x86_64
+            let target = self.target.as_deref().unwrap_or_default();
+            let gc64 =
+                is_64bit && (!target.starts_with("x86_64") || self.options.gc64 != Some(false));
+            if gc64 != expected {
+                let msg = format!(
+                    "GC64 is {} but {target} would build a {} VM",
+                    if expected { "required" } else { "forbidden" },
+                    if gc64 { "GC64" } else { "non-GC64" }
+                );
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if self.options.require_ffi && self.options.disable_ffi {
+            return Err(Error::InvalidOption(
+                "the FFI is required but disabled".to_string(),
+            ));
+        }
+        if self.options.require_jit && self.jit_disabled() {
+            let target = self.target.as_deref().unwrap_or_default();
+            let msg = format!("the JIT compiler is required but unavailable for {target}");
+            return Err(Error::InvalidOption(msg));
+        }
+        if self.options.allocator != Allocator::Internal
+            && self.options.gc64 == Some(false)
+            && is_64bit
+        {
+            return Err(Error::InvalidOption(
+                "the system allocator cannot be used with GC64 disabled".to_string(),
+            ));
+        }
+        if let Some(width) = self.options.pointer_width {
+            if width != 32 && width != 64 {
+                let msg = format!("pointer_width must be 32 or 64, got {width}");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if self.options.combined_object && self.options.shared {
+            return Err(Error::InvalidOption(
+                "combined_object requires a static build".to_string(),
+            ));
+        }
+        if self.options.keep_objects && self.options.shared {
+            return Err(Error::InvalidOption(
+                "keep_objects requires a static build".to_string(),
+            ));
+        }
+        if self.options.cache_dir.is_some() && self.options.in_place {
+            return Err(Error::InvalidOption(
+                "cache_dir cannot be combined with in_place builds".to_string(),
+            ));
+        }
+        for lib in &self.options.excluded_libs {
+            if !STD_LIBS.contains(&&lib[..]) {
+                return Err(Error::InvalidOption(format!(
+                    "unknown standard library `{lib}`"
+                )));
+            }
+        }
+        let excludes_package = self
+            .options
+            .excluded_libs
+            .iter()
+            .any(|lib| lib == "package");
+        if excludes_package && !self.options.embedded_modules.is_empty() {
+            return Err(Error::InvalidOption(
+                "embedded modules require the package library".to_string(),
+            ));
+        }
+        if self.options.version_script.is_some() && !self.options.shared {
+            return Err(Error::InvalidOption(
+                "version_script requires a shared build".to_string(),
+            ));
+        }
+        if self.options.thin_archive && self.options.shared {
+            return Err(Error::InvalidOption(
+                "thin_archive requires a static build".to_string(),
+            ));
+        }
+        for feature in self.options.x86_features.iter().flatten() {
+            if !X86_FEATURES.contains(&&feature[..]) {
+                let msg = format!("unknown x86 feature `{feature}`");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if let Some(handler) = &self.options.assert_handler {
+            let is_ident = !handler.is_empty()
+                && !handler.starts_with(|c: char| c.is_ascii_digit())
+                && handler
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_ident {
+                let msg = format!("assert_handler must be a C identifier, got `{handler}`");
+                return Err(Error::InvalidOption(msg));
+            }
+            if !self.options.assertions {
+                return Err(Error::InvalidOption(
+                    "assert_handler requires assertions".to_string(),
+                ));
+            }
+        }
+        if let Some(size) = self.options.max_page_size {
+            if size < 4096 || !size.is_power_of_two() {
+                let msg = format!("max_page_size must be a power of two >= 4096, got {size}");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if let Some(version) = self.options.dwarf_version {
+            if !(2..=5).contains(&version) {
+                let msg = format!("dwarf_version must be between 2 and 5, got {version}");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if let Some(pause) = self.options.gc_pause {
+            if !(50..=1000).contains(&pause) {
+                let msg = format!("gc_pause must be between 50 and 1000, got {pause}");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if let Some(step_mul) = self.options.gc_step_mul {
+            if !(100..=1000).contains(&step_mul) {
+                let msg = format!("gc_step_mul must be between 100 and 1000, got {step_mul}");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if let Some(level) = self.options.default_jit_opt {
+            if level > 3 {
+                let msg = format!("default_jit_opt must be between 0 and 3, got {level}");
+                return Err(Error::InvalidOption(msg));
+            }
+        }
+        if !self.options.rpaths.is_empty() && !self.options.shared {
+            return Err(Error::InvalidOption(
+                "rpath requires a shared build".to_string(),
+            ));
+        }
+        if self
+            .target
+            .as_ref()
+            .is_some_and(|target| target.contains("msvc"))
+        {
+            self.validate_msvc()?;
+        }
+        Ok(())
+    }
+
+    // Options `msvcbuild.bat` has no equivalent for
+    fn validate_msvc(&self) -> Result<(), Error> {
+        if self.options.host_tools_only {
+            let msg = "building only the host tools is not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.combined_object {
+            let msg = "combined objects are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.dwarf_version.is_some() {
+            let msg = "MSVC does not emit DWARF debug information";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.hermetic {
+            let msg = "hermetic builds are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.thin_archive {
+            let msg = "thin archives are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.pgo.is_some() {
+            let msg = "PGO builds are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.link_map {
+            let msg = "linker maps are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.in_place {
+            let msg = "in-place builds are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.version_script.is_some() {
+            let msg = "version scripts are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.thin_lto {
+            let msg = "ThinLTO is not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.compiler_launcher.is_some() || self.options.host_compiler_launcher.is_some()
+        {
+            let msg = "compiler launchers are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.keep_objects {
+            let msg = "keeping objects is not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.remap_paths {
+            let msg = "remapping paths is not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        if self.options.cache_dir.is_some() {
+            let msg = "build caches are not supported for MSVC";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        Ok(())
+    }
+
+    // Extra system libraries the final binary has to link
+    fn system_libs(&self, target: &str) -> Result<Vec<String>, Error> {
+        let mut system_libs = Vec::new();
+
+        let link_libucontext = match self.options.link_libucontext {
+            Some(enabled) => enabled,
+            None if target.contains("musl") || target.contains("uclibc") => {
+                let src_dir = self.resolved_source_dir().join("src");
+                let entries = fs::read_dir(&src_dir)
+                    .context(|| format!("cannot read {}", src_dir.display()))?;
+                entries.filter_map(Result::ok).any(|entry| {
+                    let path = entry.path();
+                    path.extension().is_some_and(|ext| ext == "c" || ext == "h")
+                        && fs::read_to_string(&path)
+                            .is_ok_and(|s| s.contains("makecontext(") || s.contains("swapcontext("))
+                })
+            }
+            None => false,
+        };
+        if link_libucontext {
+            system_libs.push("ucontext".to_string());
+        }
+        if target.contains("haiku") {
+            system_libs.push("root".to_string());
+            system_libs.push("network".to_string());
+        }
+        match self.options.allocator {
+            Allocator::Jemalloc => system_libs.push("jemalloc".to_string()),
+            Allocator::Tcmalloc => system_libs.push("tcmalloc".to_string()),
+            Allocator::Internal | Allocator::System => {}
+        }
+
+        Ok(system_libs)
+    }
+
+    fn link_args(&self, target: &str, artifacts: &Artifacts) -> Result<Vec<String>, Error> {
+        let mut link_args = Vec::new();
+        let is_apple = target.contains("apple");
+        let is_elf = !is_apple && !target.contains("windows");
+
+        if self.options.hide_symbols {
+            if is_elf {
+                for lib in artifacts
+                    .libs
+                    .iter()
+                    .filter(|lib| lib.kind == LinkKind::Static)
+                {
+                    link_args.push(format!("-Wl,--exclude-libs,lib{}.a", lib.name));
+                }
+            } else if is_apple {
+                let list = artifacts.lib_dir.join("luajit.unexported");
+                fs::write(&list, "_lua_*\n_luaL_*\n_luaJIT_*\n_luaopen_*\n")
+                    .context(|| format!("cannot write {}", list.display()))?;
+                link_args.push(format!("-Wl,-unexported_symbols_list,{}", list.display()));
+            }
+        }
+
+        if let Some(size) = self.resolved_max_page_size(target) {
+            link_args.push(format!("-Wl,-z,max-page-size={size}"));
+        }
+
+        if self.options.link_map {
+            link_args.push(link_map_flag(target, &artifacts.lib_dir.join("final.map")));
+        }
+
+        // The instrumented code needs the profiling runtime
+        if let Some(PgoStage::Generate(_)) = &self.options.pgo {
+            link_args.extend(self.pgo_flags(artifacts.build_info.compiler_family)?);
+        }
+
+        for rpath in &self.options.rpaths {
+            if is_apple {
+                let rpath = rpath.replace("$ORIGIN", "@loader_path");
+                link_args.push(format!("-Wl,-rpath,{rpath}"));
+            } else if is_elf {
+                let rpath = rpath
+                    .replace("@loader_path", "$ORIGIN")
+                    .replace("@executable_path", "$ORIGIN");
+                link_args.push(format!("-Wl,-rpath,{rpath}"));
+            }
+        }
+
+        if self.options.export_dynamic {
+            if is_elf {
+                link_args.push("-rdynamic".to_string());
+            } else if is_apple {
+                link_args.push("-Wl,-export_dynamic".to_string());
+            }
+        }
+
+        Ok(link_args)
+    }
+
+    pub fn build_unix(&mut self) -> Artifacts {
+        self.try_build_unix().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    fn try_build_unix(&mut self) -> Result<Artifacts, Error> {
+        let target = &self
+            .target
+            .as_ref()
+            .ok_or(Error::MissingSetting("TARGET"))?[..];
+        let host = &self.host.as_ref().ok_or(Error::MissingSetting("HOST"))?[..];
+        let out_dir = &self.resolved_out_dir(target);
+        let source_dir = self.resolved_source_dir();
+        let stamp = self.source_stamp(&source_dir)?;
+        let copy_started = Instant::now();
+        let (build_dir, lib_dir, include_dir, make_clean) = if self.options.in_place {
+            self.prepare_in_place(out_dir, &source_dir, &stamp)?
+        } else {
+            let changed_headers = self.changed_headers(&out_dir.join("build"), &stamp);
+            let (build_dir, lib_dir, include_dir) =
+                self.prepare_dirs(out_dir, changed_headers.is_none())?;
+            match &changed_headers {
+                // Only headers changed, let make rebuild their dependents
+                Some(headers) => {
+                    for header in headers {
+                        let (src, dst) = (source_dir.join(header), build_dir.join(header));
+                        copy_file(&src, &dst).context(|| {
+                            format!("cannot copy {} to {}", src.display(), dst.display())
+                        })?;
+                    }
+                }
+                None => self.copy_sources(&source_dir, &build_dir)?,
+            }
+            (build_dir, lib_dir, include_dir, false)
+        };
+        let copy = copy_started.elapsed();
+
+        let is_ppc64 = target.starts_with("powerpc64");
+        if is_ppc64 {
+            // Upstream v2.1 only has a 32-bit PPC backend
+            let arch_h = fs::read_to_string(build_dir.join("src").join("lj_arch.h"))
+                .context(|| "cannot read lj_arch.h".to_string())?;
+            if arch_h.contains("No support for PPC64") {
+                return Err(Error::Unsupported(format!(
+                    "{target} requires LuaJIT sources with PPC64 support"
+                )));
+            }
+        }
+
+        let mut cc = cc::Build::new();
+        cc.target(target).host(host).warnings(false).opt_level(2);
+        let compiler = cc.get_compiler();
+        let compiler_path = compiler.path().to_str().unwrap();
+
+        let mut make = self.cmd_make()?;
+        make.current_dir(build_dir.join("src"));
+        make.arg("-e");
+        if let Some(jobs) = self.options.jobs {
+            make.arg(format!("-j{}", jobs.count()));
+        }
+
+        if let Some((var, default)) = apple_deployment_target(target) {
+            if let Some(version) = &self.options.deployment_target {
+                make.env(var, version);
+            } else if env::var_os(var).is_none() {
+                make.env(var, default);
+            }
+        }
+
+        // Otherwise the makefile guesses from `uname` of the host
+        if let Some(os) = self.options.target_os {
+            make.env("TARGET_SYS", os.target_sys());
+        } else if let Some(target_sys) = target_sys(target) {
+            make.env("TARGET_SYS", target_sys);
+        }
+        // Haiku has no separate libm/libdl, both live in libroot
+        if target.contains("haiku") && env::var_os("TARGET_XLIBS").is_none() {
+            make.env("TARGET_XLIBS", "-lroot -lnetwork");
+        }
+
+        let target_pointer_width = match self.options.pointer_width {
+            Some(width) => width.to_string(),
+            None => env::var("CARGO_CFG_TARGET_POINTER_WIDTH")
+                .map_err(|_| Error::MissingSetting("CARGO_CFG_TARGET_POINTER_WIDTH"))?,
+        };
+        let mut host_cc = match &self.options.host_cc {
+            Some(host_cc) => Some(host_cc.to_string_lossy().into_owned()),
+            None if target_pointer_width == "32" && env::var_os("HOST_CC").is_none() => {
+                // 32-bit cross-compilation?
+                let host_cc = cc::Build::new().target(host).get_compiler();
+                Some(format!("{} -m32", host_cc.path().display()))
+            }
+            None => env::var("HOST_CC").ok(),
+        };
+        if let Some(launcher) = &self.options.host_compiler_launcher {
+            let host_cc = host_cc.get_or_insert_with(|| {
+                let host_cc = cc::Build::new().target(host).host(host).get_compiler();
+                host_cc.path().display().to_string()
+            });
+            *host_cc = format!("{launcher} {host_cc}");
+        }
+        if let Some(host_cc) = host_cc {
+            make.env("HOST_CC", host_cc);
+        }
+
+        let prefix = tool_prefix(compiler_path);
+
+        let compiler_path = which::which(compiler_path)
+            .map_err(|_| Error::ToolNotFound(compiler_path.to_string()))?;
+        let bindir = compiler_path.parent().unwrap();
+        let compiler_path = compiler_path.to_str().unwrap();
+        // `CC="zig cc"`: zig does not understand the LLVM `--target=` cc adds
+        let is_zig = is_zig(&compiler);
+        let compiler_target = self.options.compiler_target.as_deref();
+        let mut compiler_args = if is_zig || (compiler_target.is_some() && compiler.is_like_clang())
+        {
+            let mut args = compiler
+                .args()
+                .iter()
+                .map(|arg| arg.to_str().unwrap())
+                .filter(|arg| !arg.starts_with("--target="))
+                .collect::<Vec<_>>()
+                .join(" ");
+            match compiler_target {
+                Some(triple) if is_zig => args.push_str(&format!(" -target {triple}")),
+                Some(triple) => args.push_str(&format!(" --target={triple}")),
+                None => args.push_str(&format!(" -target {}", zig_target(target))),
+            }
+            args
+        } else {
+            compiler.cflags_env().to_str().unwrap().to_string()
+        };
+        // Target-only, unlike `XCFLAGS` which is also used for the host tools
+        if self.options.fail_on_warnings && (compiler.is_like_gnu() || compiler.is_like_clang()) {
+            // Drop the `-w` added by `warnings(false)`
+            compiler_args = compiler_args
+                .split_whitespace()
+                .filter(|arg| *arg != "-w")
+                .collect::<Vec<_>>()
+                .join(" ");
+            compiler_args.push_str(" -Wall -Werror");
+        }
+        if self.options.remap_paths {
+            if !(compiler.is_like_gnu() || compiler.is_like_clang()) {
+                let msg = "remapping paths requires GCC or Clang";
+                return Err(Error::Unsupported(msg.to_string()));
+            }
+            let dir = build_dir.display();
+            compiler_args.push_str(&format!(
+                " -ffile-prefix-map={dir}=luajit -fdebug-prefix-map={dir}=luajit"
+            ));
+        }
+        if self.options.hardening && (compiler.is_like_gnu() || compiler.is_like_clang()) {
+            for flag in hardening_flags(target) {
+                compiler_args.push(' ');
+                compiler_args.push_str(flag);
+            }
+        }
+        if self.options.fast_math && (compiler.is_like_gnu() || compiler.is_like_clang()) {
+            compiler_args.push_str(" -ffast-math");
+        }
+        if compiler.is_like_gnu() || compiler.is_like_clang() {
+            for feature in self.resolved_x86_features(target) {
+                compiler_args.push_str(&format!(" -m{feature}"));
+            }
+        }
+        for flag in self.pgo_flags(CompilerFamily::detect(&compiler))? {
+            compiler_args.push(' ');
+            compiler_args.push_str(&flag);
+        }
+        let sysroot = match &self.options.sysroot {
+            Some(sysroot) => Some(sysroot.clone()),
+            None if compiler.is_like_gnu() => {
+                compiler_sysroot(Path::new(compiler_path), compiler.args())
+            }
+            None => None,
+        };
+        if let Some(sysroot) = sysroot {
+            compiler_args.push_str(&format!(" --sysroot={}", sysroot.display()));
+        }
+        for arg in &self.options.cc_args {
+            compiler_args.push(' ');
+            compiler_args.push_str(arg);
+        }
+        let compiler_args = &compiler_args[..];
+        let launcher = match &self.options.compiler_launcher {
+            Some(launcher) => format!("{launcher} "),
+            None => String::new(),
+        };
+        if env::var_os("STATIC_CC").is_none() {
+            make.env(
+                "STATIC_CC",
+                format!("{launcher}{compiler_path} {compiler_args}"),
+            );
+        }
+        if env::var_os("TARGET_LD").is_none() {
+            let mut target_ld = format!("{launcher}{compiler_path} {compiler_args}");
+            if let Some(size) = self.resolved_max_page_size(target) {
+                target_ld.push_str(&format!(" -Wl,-z,max-page-size={size}"));
+            }
+            make.env("TARGET_LD", target_ld);
+        }
+
+        // Find ar
+        let ar = if let Some(ar) = env::var_os("TARGET_AR") {
+            ar
+        } else {
+            let mut ar = self.find_ar(&compiler, compiler_path, is_zig)?;
+            if self.options.thin_archive {
+                if target.contains("apple") {
+                    let msg = "thin archives are not supported by the Apple archiver";
+                    return Err(Error::Unsupported(msg.to_string()));
+                }
+                ar.push(" rcusT");
+            } else {
+                ar.push(" rcus");
+            }
+            make.env("TARGET_AR", &ar);
+            ar
+        };
+
+        // Find strip
+        let strip = if self.options.strip == Some(StripMode::None) {
+            make.env("TARGET_STRIP", ":");
+            None
+        } else if let Some(strip) = env::var_os("TARGET_STRIP") {
+            Some(strip)
+        } else if is_zig && env::var_os("STRIP").is_none() {
+            // zig has no strip subcommand, leave the interpreter unstripped
+            make.env("TARGET_STRIP", ":");
+            None
+        } else {
+            let mut strip = self.find_strip(&compiler, compiler_path)?.into_os_string();
+            match self.options.strip {
+                Some(StripMode::DebugOnly) => strip.push(" -S"),
+                Some(StripMode::SymbolsOnly) => strip.push(" -x"),
+                _ => {}
+            }
+            make.env("TARGET_STRIP", &strip);
+            Some(strip)
+        };
+
+        let mut xcflags = vec!["-fPIC".to_string()];
+        if self.options.lua52compat {
+            xcflags.push("-DLUAJIT_ENABLE_LUA52COMPAT".to_string());
+        }
+        for define in self.common_defines() {
+            xcflags.push(format!("-D{define}"));
+        }
+        if self.options.runtime_jit_probe
+            && self.options.disable_jit.is_none()
+            && !self.jit_disabled()
+            && target == host
+            && !self.probe_jit(compiler_path, compiler_args, out_dir)?
+        {
+            if self.options.require_jit {
+                return Err(Error::InvalidOption(
+                    "the JIT compiler is required but this machine forbids executable memory"
+                        .to_string(),
+                ));
+            }
+            self.warn(
+                "building LuaJIT without the JIT compiler, this machine forbids executable memory"
+                    .to_string(),
+            );
+            xcflags.push("-DLUAJIT_DISABLE_JIT".to_string());
+        }
+        if self.options.thin_lto {
+            xcflags.push("-flto=thin".to_string());
+        } else if self.options.lto {
+            xcflags.push("-flto".to_string());
+        }
+        // Objects are compiled from relative paths inside `src`, so archive members
+        // are already path-free. Keep the build directory out of debug info too.
+        // The compiler picks the last matching map, leave `remap_paths` alone.
+        if (compiler.is_like_gnu() || compiler.is_like_clang()) && !self.options.remap_paths {
+            let src_dir = build_dir.join("src");
+            xcflags.push(format!("-fdebug-prefix-map={}=.", src_dir.display()));
+        }
+        for dir in self.resolved_include_dirs()? {
+            xcflags.push(format!("-I{}", dir.display()));
+        }
+        match self.options.stack_protector {
+            Some(true) => xcflags.push("-fstack-protector-strong".to_string()),
+            Some(false) => xcflags.push("-fno-stack-protector".to_string()),
+            None => {}
+        }
+        if let Some(ccdebug) = self.ccdebug() {
+            make.env("CCDEBUG", ccdebug.join(" "));
+        }
+        if is_ppc64 {
+            let endian = if target.starts_with("powerpc64le") {
+                "-mlittle-endian"
+            } else {
+                "-mbig-endian"
+            };
+            xcflags.push(endian.to_string());
+        }
+
+        if self.options.shared {
+            make.env("BUILDMODE", "dynamic");
+            if target.contains("apple") {
+                make.env("TARGET_DYLIBPATH", "@rpath/libluajit-5.1.dylib");
+            }
+        } else {
+            make.env("BUILDMODE", "static");
+        }
+        // A plain `-pie` would look for a dynamic libc, which static musl lacks
+        let crt_static = target.contains("musl")
+            && !self.options.shared
+            && env::var("CARGO_CFG_TARGET_FEATURE")
+                .is_ok_and(|features| features.split(',').any(|f| f == "crt-static"));
+        let pie_flag = match (self.options.pie, target.contains("apple")) {
+            (Some(true) | None, _) if crt_static => Some("-static-pie"),
+            (Some(false), _) if crt_static => Some("-static"),
+            (Some(true), true) => Some("-Wl,-pie"),
+            (Some(true), false) => Some("-pie"),
+            (Some(false), true) => Some("-Wl,-no_pie"),
+            (Some(false), false) => Some("-no-pie"),
+            (None, _) => None,
+        };
+        if let Some(flag) = pie_flag {
+            // `TARGET_LDFLAGS` only reaches the `luajit` link, not the host tools
+            if (compiler.is_like_gnu() || compiler.is_like_clang()) && !target.contains("windows") {
+                append_make_flag(&mut make, "TARGET_LDFLAGS", flag);
+            }
+        }
+        if self.options.thin_lto && !compiler.is_like_clang() {
+            let msg = "ThinLTO requires Clang";
+            return Err(Error::Unsupported(msg.to_string()));
+        }
+        let link_map = if self.options.link_map {
+            if !(compiler.is_like_gnu() || compiler.is_like_clang()) {
+                let msg = "linker maps require GCC or Clang";
+                return Err(Error::Unsupported(msg.to_string()));
+            }
+            let map = lib_dir.join("luajit.map");
+            let flag = link_map_flag(target, &map);
+            // Only the link of the installed artifact, the other one keeps its flags
+            let var = if self.options.shared {
+                "TARGET_SHLDFLAGS"
+            } else {
+                "TARGET_LDFLAGS"
+            };
+            append_make_flag(&mut make, var, &flag);
+            Some(map)
+        } else {
+            None
+        };
+        let version_script = match &self.options.version_script {
+            Some(script) => {
+                if target.contains("apple") || target.contains("windows") {
+                    let msg = "version scripts are only supported for ELF targets";
+                    return Err(Error::Unsupported(msg.to_string()));
+                }
+                let script = match script {
+                    Some(script) => env::current_dir()
+                        .context(|| "cannot get current directory".to_string())?
+                        .join(script),
+                    None => {
+                        let script = lib_dir.join("luajit.ver");
+                        fs::write(&script, DEFAULT_VERSION_SCRIPT)
+                            .context(|| format!("cannot write {}", script.display()))?;
+                        script
+                    }
+                };
+                let flag = format!("-Wl,--version-script={}", script.display());
+                append_make_flag(&mut make, "TARGET_SHLDFLAGS", &flag);
+                Some(script)
+            }
+            None => None,
+        };
+        make.env("XCFLAGS", xcflags.join(" "));
+        if make_clean {
+            let mut clean = clone_command(&make);
+            clean.arg("clean");
+            self.run_command(&mut clean, "cleaning LuaJIT build")?;
+        }
+
+        let build_info = BuildInfo {
+            target: target.to_string(),
+            host: host.to_string(),
+            compiler: compiler_path.to_string(),
+            compiler_family: CompilerFamily::detect(&compiler),
+            compiler_version: compiler_version(Path::new(compiler_path), "--version"),
+            compiler_args: compiler_args.to_string(),
+            ar: Some(ar.to_string_lossy().into_owned()),
+            strip: strip.map(|s| s.to_string_lossy().into_owned()),
+            cflags: xcflags.clone(),
+        };
+
+        let compile_commands = if self.options.compile_commands {
+            Some(self.write_compile_commands(&build_dir, &build_info)?)
+        } else {
+            None
+        };
+
+        if self.options.host_tools_only {
+            make.args(["host/minilua", "host/buildvm"]);
+            let host_tools_started = Instant::now();
+            self.run_command(&mut make, "building LuaJIT host tools")?;
+            let timings = Timings {
+                copy,
+                host_tools: Some(host_tools_started.elapsed()),
+                compile: Duration::ZERO,
+            };
+
+            let bin_dir = out_dir.join("bin");
+            fs::create_dir_all(&bin_dir)
+                .context(|| format!("cannot create {}", bin_dir.display()))?;
+            let exe_suffix = if host.contains("windows") { ".exe" } else { "" };
+            let install = |name: &str| {
+                let name = format!("{name}{exe_suffix}");
+                let installed = bin_dir.join(&name);
+                copy_file(&build_dir.join("src").join("host").join(&name), &installed)
+                    .context(|| format!("cannot install {name}"))?;
+                Ok::<_, Error>(installed)
+            };
+            let host_tools = HostTools {
+                minilua: install("minilua")?,
+                buildvm: install("buildvm")?,
+            };
+
+            return Ok(Artifacts {
+                target: target.to_string(),
+                lib_dir,
+                include_dir,
+                libs: Vec::new(),
+                executable: None,
+                test_dir: None,
+                link_args: Vec::new(),
+                system_libs: Vec::new(),
+                link_search: Vec::new(),
+                warnings: Vec::new(),
+                glibc_symbols: Vec::new(),
+                shared_lib: None,
+                build_info,
+                host_tools: Some(host_tools),
+                compile_commands,
+                whole_archive: self.options.whole_archive,
+                combined_object: None,
+                system: false,
+                timings: self.finish_timings(timings),
+                link_map: None,
+                version_script: None,
+                object_dir: None,
+                jit_dir: None,
+                cache_hit: false,
+                self_check: None,
+            });
+        }
+
+        let embed_modules = !self.options.embedded_modules.is_empty();
+        if embed_modules && target != host {
+            // The built interpreter cannot produce bytecode for us
+            self.embed_modules(&build_dir, None)?;
+        }
+        let lib = build_dir.join("src").join(if self.options.shared {
+            "libluajit.so"
+        } else {
+            "libluajit.a"
+        });
+        let cache_entry = self
+            .options
+            .cache_dir
+            .as_ref()
+            .map(|cache_dir| cache_dir.join(cache_key(&stamp, &build_dir)));
+        let cache_hit = cache_entry.as_ref().is_some_and(|entry| entry.is_dir());
+        // Build the host tools on their own first so they can be timed separately
+        let host_tools = if self.options.report_timings && !cache_hit {
+            let mut host_make = clone_command(&make);
+            host_make.args(["host/minilua", "host/buildvm"]);
+            let started = Instant::now();
+            self.run_command(&mut host_make, "building LuaJIT host tools")?;
+            Some(started.elapsed())
+        } else {
+            None
+        };
+        let compile_started = Instant::now();
+        match &cache_entry {
+            Some(entry) if cache_hit => {
+                if let Some(path) = &self.options.build_script {
+                    self.write_build_script_to(path, &make)?;
+                }
+                self.restore_cached(entry, &build_dir, link_map.as_deref())?;
+                if !lib.is_file() {
+                    return Err(Error::LibraryNotProduced(lib));
+                }
+            }
+            _ => {
+                self.run_build(&mut make, "building LuaJIT", &lib)?;
+                if embed_modules && target == host {
+                    let luajit = build_dir.join("src").join("luajit");
+                    self.embed_modules(&build_dir, Some(&luajit))?;
+                    self.run_build(&mut make, "building LuaJIT with embedded modules", &lib)?;
+                }
+                if let Some(entry) = &cache_entry {
+                    self.store_cached(entry, &source_dir, &build_dir, link_map.as_deref())?;
+                }
+            }
+        }
+        // Upstream links the shared library as `libluajit-5.1.so.2`, the name the
+        // executable and anything linked against it look up at runtime
+        let is_elf = !target.contains("apple") && !target.contains("windows");
+        let soname = if self.options.shared && is_elf {
+            let major = LUAJIT_VERSION.split('.').next().unwrap();
+            let soname = format!("libluajit-5.1.so.{major}");
+            copy_file(&lib, &build_dir.join("src").join(&soname))
+                .context(|| format!("cannot copy libluajit.so to {soname}"))?;
+            Some(soname)
+        } else {
+            None
+        };
+        let self_check = if self.options.self_check && target == host {
+            let exe = if target.contains("windows") {
+                "luajit.exe"
+            } else {
+                "luajit"
+            };
+            Some(self.run_self_check(&build_dir.join("src").join(exe))?)
+        } else {
+            None
+        };
+        let timings = Timings {
+            copy,
+            host_tools,
+            compile: compile_started.elapsed(),
+        };
+
+        // Find ranlib. Cross toolchains need an explicit pass, and so do Apple
+        // and BSD linkers, which reject an archive copied after `ar s` indexed it.
+        let ranlib = if let Some(ranlib) = &self.options.ranlib {
+            Some(ranlib.clone())
+        } else if let Some(ranlib) = env::var_os("RANLIB") {
+            Some(ranlib)
+        } else if self.options.lto
+            && !prefix.is_empty()
+            && compiler.is_like_gnu()
+            && bindir.join(format!("{prefix}gcc-ranlib")).is_file()
+        {
+            Some(bindir.join(format!("{prefix}gcc-ranlib")).into_os_string())
+        } else if !prefix.is_empty() && bindir.join(format!("{prefix}ranlib")).is_file() {
+            Some(bindir.join(format!("{prefix}ranlib")).into_os_string())
+        } else if !prefix.is_empty()
+            && compiler.is_like_clang()
+            && bindir.join("llvm-ranlib").is_file()
+        {
+            Some(bindir.join("llvm-ranlib").into_os_string())
+        } else if (target.contains("apple") && host.contains("apple"))
+            || (target.contains("bsd") && host == target)
+        {
+            Some(OsString::from("ranlib"))
+        } else {
+            None
+        };
+
+        // Versions are only bound at link time, so look at the linked binaries
+        let glibc_symbols = if target.contains("linux-gnu") {
+            let binary = match self.options.shared {
+                true => lib.clone(),
+                false => build_dir.join("src").join("luajit"),
+            };
+            let objdump = Some(bindir.join(format!("{prefix}objdump")))
+                .filter(|path| path.is_file())
+                .or_else(|| which::which("objdump").ok());
+            match objdump {
+                Some(objdump) if binary.is_file() => glibc_symbols(&objdump, &binary),
+                _ => Vec::new(),
+            }
+        } else {
+            Vec::new()
+        };
+        if let Some(max) = &self.options.max_glibc_version {
+            let newer = glibc_symbols
+                .iter()
+                .filter(|symbol| {
+                    let version = symbol.rsplit("@GLIBC_").next().unwrap_or_default();
+                    version_key(version) > version_key(max)
+                })
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            if !newer.is_empty() {
+                self.warn(format!(
+                    "LuaJIT references glibc symbols newer than {max}: {}",
+                    newer.join(", ")
+                ));
+            }
+        }
+
+        install_headers(&build_dir, &include_dir)?;
+        let ext = if !self.options.shared {
+            "a"
+        } else if target.contains("apple") {
+            "dylib"
+        } else {
+            "so"
+        };
+        let shared_lib = if self.options.shared {
+            let name = format!("libluajit-5.1.{ext}");
+            copy_file(&lib, &lib_dir.join(&name))
+                .context(|| "cannot install libluajit.so".to_string())?;
+            if let Some(soname) = &soname {
+                copy_file(&lib, &lib_dir.join(soname))
+                    .context(|| format!("cannot install {soname}"))?;
+            }
+            Some(lib_dir.join(name))
+        } else {
+            self.install_archive(&ar, &lib, &lib_dir.join("libluajit-5.1.a"))?;
+            self.index_archive(ranlib.as_deref(), &lib_dir.join("libluajit-5.1.a"))?;
+            None
+        };
+        let lib_name = if self.options.neutral_lib_name {
+            // The shared library keeps its real name, which the executable's
+            // loader looks up at runtime
+            let alias = lib_dir.join(format!("libluajit.{ext}"));
+            if self.options.shared {
+                copy_file(&lib, &alias).context(|| format!("cannot install libluajit.{ext}"))?;
+            } else {
+                self.install_archive(&ar, &lib, &alias)?;
+                self.index_archive(ranlib.as_deref(), &alias)?;
+            }
+            "luajit"
+        } else {
+            "luajit-5.1"
+        };
+
+        let combined_object = if self.options.combined_object {
+            let object = lib_dir.join("luajit.o");
+            let archive = build_dir.join("src").join("libluajit.a");
+            let mut ld = Command::new(compiler_path);
+            ld.args(compiler_args.split_whitespace())
+                .args(["-r", "-nostdlib"]);
+            // Let the LTO plugin emit native code instead of an LTO object
+            if self.options.lto && compiler.is_like_gnu() {
+                ld.args(["-flto", "-flinker-output=nolto-rel"]);
+            } else if self.options.thin_lto {
+                ld.arg("-flto=thin");
+            } else if self.options.lto {
+                ld.arg("-flto");
+            }
+            if target.contains("apple") {
+                ld.arg("-Wl,-all_load").arg(&archive);
+            } else {
+                ld.arg("-Wl,--whole-archive")
+                    .arg(&archive)
+                    .arg("-Wl,--no-whole-archive");
+            }
+            ld.arg("-o").arg(&object);
+            self.run_command(&mut ld, "linking combined LuaJIT object")?;
+            Some(object)
+        } else {
+            None
+        };
+
+        let object_dir = if self.options.keep_objects {
+            let object_dir = out_dir.join("objects");
+            if object_dir.exists() {
+                fs::remove_dir_all(&object_dir)
+                    .context(|| format!("cannot remove {}", object_dir.display()))?;
+            }
+            fs::create_dir_all(&object_dir)
+                .context(|| format!("cannot create {}", object_dir.display()))?;
+            let members = archive_members(&ar, &build_dir.join("src").join("libluajit.a"))?;
+            // Apple's `ar t` also lists the symbol index
+            for member in members
+                .iter()
+                .filter(|m| m.extension().is_some_and(|e| e == "o"))
+            {
+                let dst = object_dir.join(member.file_name().unwrap());
+                copy_file(member, &dst).context(|| format!("cannot copy {}", member.display()))?;
+            }
+            Some(object_dir)
+        } else {
+            None
+        };
+
+        let (executable, test_dir) = if self.options.build_tests {
+            install_tests(&source_dir, &build_dir.join("src").join("luajit"), out_dir)?
+        } else {
+            (None, None)
+        };
+
+        let stamp_file = build_dir.join(STAMP_FILE);
+        fs::write(&stamp_file, stamp)
+            .context(|| format!("cannot write {}", stamp_file.display()))?;
+
+        let lib_kind = if self.options.shared {
+            LinkKind::Dylib
+        } else {
+            LinkKind::Static
+        };
+        Ok(Artifacts {
+            target: target.to_string(),
+            lib_dir,
+            include_dir,
+            libs: vec![Lib::new(lib_name, lib_kind)],
+            executable,
+            test_dir,
+            link_args: Vec::new(),
+            system_libs: Vec::new(),
+            link_search: Vec::new(),
+            warnings: Vec::new(),
+            glibc_symbols,
+            shared_lib,
+            build_info,
+            host_tools: None,
+            compile_commands,
+            whole_archive: self.options.whole_archive,
+            combined_object,
+            system: false,
+            timings: self.finish_timings(timings),
+            link_map,
+            version_script,
+            object_dir,
+            jit_dir: Some(build_dir.join("src").join("jit")),
+            cache_hit,
+            self_check,
+        })
+    }
+
+    pub fn build_msvc(&mut self) -> Artifacts {
+        self.validate()
+            .and_then(|()| self.try_build_msvc())
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    fn try_build_msvc(&mut self) -> Result<Artifacts, Error> {
+        let target = &self
+            .target
+            .as_ref()
+            .ok_or(Error::MissingSetting("TARGET"))?[..];
+        let out_dir = &self.resolved_out_dir(target);
+        let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let source_dir = self.resolved_source_dir();
+        let extras_dir = manifest_dir.join("extras");
+        let (build_dir, lib_dir, include_dir) = self.prepare_dirs(out_dir, true)?;
+        let copy_started = Instant::now();
+        self.copy_sources(&source_dir, &build_dir)?;
+        let copy = copy_started.elapsed();
+
+        let script = match &self.options.msvc_build_script {
+            Some(script) => env::current_dir()
+                .context(|| "cannot get current directory".to_string())?
+                .join(script),
+            None => build_dir.join("src").join("msvcbuild.bat"),
+        };
+        let mut msvcbuild = Command::new(script);
+        msvcbuild.current_dir(build_dir.join("src"));
+        if self.options.lua52compat {
+            cp_r(&extras_dir, &build_dir.join("src"))?;
+        }
+        if let Some(args) = &self.options.msvc_build_args {
+            msvcbuild.args(args);
+        } else {
+            if self.options.gc64 == Some(false) {
+                // Also switches x64 to the non-GC64 interpreter
+                msvcbuild.arg("nogc64");
+            }
+            if self.options.lua52compat {
+                msvcbuild.arg("lua52c");
+            }
+            if !self.options.shared {
+                msvcbuild.arg("static");
+            }
+        }
+        if !self.options.embedded_modules.is_empty() {
+            self.embed_modules(&build_dir, None)?;
+        }
+
+        let cl = cc::windows_registry::find_tool(target, "cl.exe")
+            .ok_or_else(|| Error::ToolNotFound("cl.exe".to_string()))?;
+        for (k, v) in cl.get_envs() {
+            msvcbuild.env(k, v);
+        }
+
+        // `CL` is picked up by every `cl` invocation in msvcbuild.bat
+        let mut cl_flags = Vec::new();
+        if let Some(jobs) = self.options.jobs {
+            cl_flags.push(format!("/MP{}", jobs.count()));
+        }
+        if self.options.debug_info {
+            cl_flags.push("/Zi".to_string());
+        }
+        match self.options.stack_protector {
+            Some(true) => cl_flags.push("/GS".to_string()),
+            Some(false) => cl_flags.push("/GS-".to_string()),
+            None => {}
+        }
+        if self.options.fast_math {
+            cl_flags.push("/fp:fast".to_string());
+        }
+        if self.options.fail_on_warnings {
+            cl_flags.push("/W3".to_string());
+            cl_flags.push("/WX".to_string());
+        }
+        // SSE4 has no `/arch` of its own
+        let x86_features = self.resolved_x86_features(target);
+        if x86_features.iter().any(|f| f == "avx2") {
+            cl_flags.push("/arch:AVX2".to_string());
+        } else if x86_features.iter().any(|f| f == "avx") {
+            cl_flags.push("/arch:AVX".to_string());
+        }
+        for define in self.common_defines() {
+            cl_flags.push(format!("/D{define}"));
+        }
+        for dir in self.resolved_include_dirs()? {
+            cl_flags.push(format!("/I{}", dir.display()));
+        }
+        cl_flags.extend(self.options.cc_args.iter().cloned());
+        if !cl_flags.is_empty() {
+            msvcbuild.env("CL", cl_flags.join(" "));
+        }
+
+        let build_info = BuildInfo {
+            target: target.to_string(),
+            host: self.host.clone().unwrap_or_default(),
+            compiler: cl.path().display().to_string(),
+            compiler_family: CompilerFamily::detect(&cl),
+            compiler_version: None,
+            compiler_args: String::new(),
+            ar: None,
+            strip: None,
+            cflags: cl_flags,
+        };
+
+        let lib = build_dir.join("src").join("lua51.lib");
+        let compile_started = Instant::now();
+        self.run_build(&mut msvcbuild, "building LuaJIT", &lib)?;
+        let self_check = if self.options.self_check && self.host.as_deref() == Some(target) {
+            Some(self.run_self_check(&build_dir.join("src").join("luajit.exe"))?)
+        } else {
+            None
+        };
+        let timings = Timings {
+            copy,
+            host_tools: None,
+            compile: compile_started.elapsed(),
+        };
+
+        install_headers(&build_dir, &include_dir)?;
+        // The import library keeps its name so it matches the DLL
+        let (lib_name, shared_lib) = if self.options.shared {
+            let dll = lib_dir.join("lua51.dll");
+            copy_file(&build_dir.join("src").join("lua51.dll"), &dll)
+                .context(|| "cannot install lua51.dll".to_string())?;
+            ("lua51", Some(dll))
+        } else {
+            ("luajit", None)
+        };
+        copy_file(
+            &build_dir.join("src").join("lua51.lib"),
+            &lib_dir.join(format!("{lib_name}.lib")),
+        )
+        .context(|| "cannot install lua51.lib".to_string())?;
+        let lib_name = if self.options.neutral_lib_name && lib_name != "luajit" {
+            copy_file(&lib, &lib_dir.join("luajit.lib"))
+                .context(|| "cannot install luajit.lib".to_string())?;
+            "luajit"
+        } else {
+            lib_name
+        };
+
+        let (executable, test_dir) = if self.options.build_tests {
+            install_tests(
+                &source_dir,
+                &build_dir.join("src").join("luajit.exe"),
+                out_dir,
+            )?
+        } else {
+            (None, None)
+        };
+
+        let lib_kind = if self.options.shared {
+            LinkKind::Dylib
+        } else {
+            LinkKind::Static
+        };
+        Ok(Artifacts {
+            target: target.to_string(),
+            lib_dir,
+            include_dir,
+            libs: vec![Lib::new(lib_name, lib_kind)],
+            executable,
+            test_dir,
+            link_args: Vec::new(),
+            system_libs: Vec::new(),
+            link_search: Vec::new(),
+            warnings: Vec::new(),
+            glibc_symbols: Vec::new(),
+            shared_lib,
+            build_info,
+            host_tools: None,
+            compile_commands: None,
+            whole_archive: self.options.whole_archive,
+            combined_object: None,
+            system: false,
+            timings: self.finish_timings(timings),
+            link_map: None,
+            version_script: None,
+            object_dir: None,
+            jit_dir: Some(build_dir.join("src").join("jit")),
+            cache_hit: false,
+            self_check,
+        })
+    }
+
+    // Reconstructs the compiler invocations of `src/Makefile` for each source file
+    fn write_compile_commands(&self, build_dir: &Path, info: &BuildInfo) -> Result<PathBuf, Error> {
+        let src_dir = build_dir.join("src");
+        let entries =
+            fs::read_dir(&src_dir).context(|| format!("cannot read {}", src_dir.display()))?;
+        let mut sources = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "c"))
+            .filter(|path| !path.ends_with("ljamalg.c"))
+            .collect::<Vec<_>>();
+        sources.sort();
+
+        let mut args = vec![info.compiler.clone()];
+        args.extend(info.compiler_args.split_whitespace().map(String::from));
+        args.extend(["-O2", "-fomit-frame-pointer", "-Wall"].map(String::from));
+        args.extend(self.ccdebug().unwrap_or_default());
+        args.extend(info.cflags.iter().cloned());
+        args.extend(
+            [
+                "-D_FILE_OFFSET_BITS=64",
+                "-D_LARGEFILE_SOURCE",
+                "-U_FORTIFY_SOURCE",
+            ]
+            .map(String::from),
+        );
+
+        let directory = json_string(&src_dir.display().to_string());
+        let commands = sources
+            .iter()
+            .map(|source| {
+                let file = source.display().to_string();
+                let arguments = args
+                    .iter()
+                    .map(String::as_str)
+                    .chain(["-c", &file])
+                    .map(json_string)
+                    .collect::<Vec<_>>();
+                format!(
+                    "  {{\n    \"directory\": {directory},\n    \"file\": {},\n    \
+                     \"arguments\": [{}]\n  }}",
+                    json_string(&file),
+                    arguments.join(", "),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let path = build_dir.join("compile_commands.json");
+        fs::write(&path, format!("[\n{}\n]\n", commands.join(",\n")))
+            .context(|| format!("cannot write {}", path.display()))?;
+        Ok(path)
+    }
+
+    // Archiver for `compiler` (resolved to `compiler_path`), without the
+    // operation flags of `TARGET_AR`
+    fn find_ar(
+        &self,
+        compiler: &cc::Tool,
+        compiler_path: &str,
+        is_zig: bool,
+    ) -> Result<OsString, Error> {
+        let prefix = tool_prefix(compiler.path().to_str().unwrap());
+        let bindir = Path::new(compiler_path).parent().unwrap();
+        let lto_ar = if !self.options.lto {
+            None
+        } else if compiler.is_like_clang() {
+            Some("llvm-ar".to_string())
+        } else if compiler.is_like_gnu() {
+            Some(format!("{prefix}gcc-ar"))
+        } else {
+            None
+        };
+        let lto_ar = lto_ar.and_then(|name| {
+            let found = Some(bindir.join(&name))
+                .filter(|path| path.is_file())
+                .or_else(|| which::which(&name).ok());
+            if found.is_none() {
+                self.warn(format!(
+                    "{name} not found, the LTO archive may be unusable by the linker"
+                ));
+            }
+            found
+        });
+        Ok(if let Some(ar) = env::var_os("AR") {
+            ar
+        } else if is_zig {
+            format!("{compiler_path} ar").into()
+        } else if let Some(ar) = lto_ar {
+            ar.into_os_string()
+        } else if bindir.join(format!("{prefix}ar")).is_file() {
+            bindir.join(format!("{prefix}ar")).into_os_string()
+        } else if compiler.is_like_clang() && bindir.join("llvm-ar").is_file() {
+            bindir.join("llvm-ar").into_os_string()
+        } else if compiler.is_like_gnu() && bindir.join("ar").is_file() {
+            bindir.join("ar").into_os_string()
+        } else if let Ok(ar) = which::which(format!("{prefix}ar")) {
+            ar.into_os_string()
+        } else {
+            return Err(Error::ToolNotFound(format!("{prefix}ar")));
+        })
+    }
+
+    // Strip tool for `compiler` (resolved to `compiler_path`)
+    fn find_strip(&self, compiler: &cc::Tool, compiler_path: &str) -> Result<PathBuf, Error> {
+        let prefix = tool_prefix(compiler.path().to_str().unwrap());
+        let bindir = Path::new(compiler_path).parent().unwrap();
+        Ok(if let Some(strip) = env::var_os("STRIP") {
+            PathBuf::from(strip)
+        } else if bindir.join(format!("{prefix}strip")).is_file() {
+            bindir.join(format!("{prefix}strip"))
+        } else if compiler.is_like_clang() && bindir.join("llvm-strip").is_file() {
+            bindir.join("llvm-strip")
+        } else if compiler.is_like_gnu() && bindir.join("strip").is_file() {
+            bindir.join("strip")
+        } else if let Ok(strip) = which::which(format!("{prefix}strip")) {
+            strip
+        } else {
+            return Err(Error::ToolNotFound(format!("{prefix}strip")));
+        })
+    }
+
+    // Generates `lj_embedded.h` and hooks it into `luaL_openlibs`. With `luajit`
+    // given, modules are precompiled to bytecode using it.
+    fn embed_modules(&self, build_dir: &Path, luajit: Option<&Path>) -> Result<(), Error> {
+        let src_dir = build_dir.join("src");
+        let modules = &self.options.embedded_modules;
+
+        let mut header = String::from("/* Generated by luajit-src. */\n");
+        for (i, (name, source)) in modules.iter().enumerate() {
+            let chunk = match luajit {
+                Some(luajit) => {
+                    let output = src_dir.join(format!("lj_embedded_{i}.raw"));
+                    let mut bc = Command::new(luajit);
+                    bc.current_dir(&src_dir)
+                        .args(["-b", "-t", "raw"])
+                        .arg(source)
+                        .arg(&output);
+                    self.run_command(&mut bc, &format!("precompiling {name}"))?;
+                    fs::read(&output).context(|| format!("cannot read {}", output.display()))?
+                }
+                None => fs::read(source).context(|| format!("cannot read {}", source.display()))?,
+            };
+            header.push_str(&format!(
+                "static const unsigned char lj_embedded_{i}[] = {{"
+            ));
+            for (j, byte) in chunk.iter().enumerate() {
+                header.push_str(if j % 16 == 0 { "\n  " } else { " " });
+                header.push_str(&format!("{byte},"));
+            }
+            header.push_str("\n};\n");
+        }
+
+        header.push_str("static const struct { const char *name; const unsigned char *data; size_t size; } lj_embedded[] = {\n");
+        for (i, (name, _)) in modules.iter().enumerate() {
+            header.push_str(&format!(
+                "  {{ {}, lj_embedded_{i}, sizeof(lj_embedded_{i}) }},\n",
+                c_string(name)
+            ));
+        }
+        header.push_str(
+            r#"};
+
+static int lj_embedded_loader(lua_State *L)
+{
+  int i = (int)lua_tointeger(L, lua_upvalueindex(1));
+  if (luaL_loadbuffer(L, (const char *)lj_embedded[i].data, lj_embedded[i].size,
+		      lj_embedded[i].name))
+    lua_error(L);
+  lua_pushvalue(L, 1);
+  lua_call(L, 1, 1);
+  return 1;
+}
+
+static void lj_embedded_preload(lua_State *L)
+{
+  size_t i, n = sizeof(lj_embedded)/sizeof(lj_embedded[0]);
+  luaL_findtable(L, LUA_REGISTRYINDEX, "_PRELOAD", (int)n);
+  for (i = 0; i < n; i++) {
+    lua_pushinteger(L, (lua_Integer)i);
+    lua_pushcclosure(L, lj_embedded_loader, 1);
+    lua_setfield(L, -2, lj_embedded[i].name);
+  }
+  lua_pop(L, 1);
+}
+"#,
+        );
+        let header_path = src_dir.join("lj_embedded.h");
+        fs::write(&header_path, header)
+            .context(|| format!("cannot write {}", header_path.display()))?;
+
+        // Rename the original `luaL_openlibs` and wrap it
+        patch_source(
+            &src_dir,
+            "lib_init.c",
+            "LUALIB_API void luaL_openlibs(lua_State *L)",
+            "static void lj_openlibs(lua_State *L)",
+        )?;
+        let lib_init = src_dir.join("lib_init.c");
+        let mut contents =
+            fs::read_to_string(&lib_init).context(|| "cannot read lib_init.c".to_string())?;
+        contents.push_str(
+            "\n#include \"lj_embedded.h\"\n\n\
+             LUALIB_API void luaL_openlibs(lua_State *L)\n\
+             {\n  lj_openlibs(L);\n  lj_embedded_preload(L);\n}\n",
+        );
+        fs::write(&lib_init, contents).context(|| "cannot write lib_init.c".to_string())
+    }
+
+    fn resolved_max_page_size(&self, target: &str) -> Option<usize> {
+        match self.options.max_page_size {
+            Some(size) => Some(size),
+            None if target.contains("android") => Some(16384),
+            None => None,
+        }
+        .filter(|_| !target.contains("apple") && !target.contains("windows"))
+    }
+
+    // Debug info flags for unix compilers (`CCDEBUG`)
+    fn ccdebug(&self) -> Option<Vec<String>> {
+        if !self.options.debug_info {
+            return None;
+        }
+        let mut flags = vec!["-g".to_string()];
+        if let Some(version) = self.options.dwarf_version {
+            flags.push(format!("-gdwarf-{version}"));
+        }
+        Some(flags)
+    }
+
+    // Include directories relative to the build script rather than `build_dir`
+    fn resolved_include_dirs(&self) -> Result<Vec<PathBuf>, Error> {
+        if self.options.include_dirs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let cwd = env::current_dir().context(|| "cannot get current directory".to_string())?;
+        Ok(self
+            .options
+            .include_dirs
+            .iter()
+            .map(|dir| cwd.join(dir))
+            .collect())
+    }
+
+    fn resolved_x86_features(&self, target: &str) -> Vec<String> {
+        let arch = target.split('-').next().unwrap_or_default();
+        if !matches!(arch, "x86_64" | "i586" | "i686") {
+            return Vec::new();
+        }
+        let features = match &self.options.x86_features {
+            Some(features) => features.clone(),
+            None => env::var("CARGO_CFG_TARGET_FEATURE")
+                .unwrap_or_default()
+                .split(',')
+                .map(|f| f.to_string())
+                .collect(),
+        };
+        features
+            .into_iter()
+            .filter(|f| X86_FEATURES.contains(&&f[..]))
+            .collect()
+    }
+
+    fn pgo_flags(&self, family: CompilerFamily) -> Result<Vec<String>, Error> {
+        let Some(stage) = &self.options.pgo else {
+            return Ok(Vec::new());
+        };
+        // make runs in the build directory
+        let cwd = env::current_dir().context(|| "cannot get current directory".to_string())?;
+        let flag = match (stage, family) {
+            (PgoStage::Generate(dir), CompilerFamily::Gnu) => {
+                format!("-fprofile-generate={}", cwd.join(dir).display())
+            }
+            (PgoStage::Use(dir), CompilerFamily::Gnu) => {
+                format!("-fprofile-use={}", cwd.join(dir).display())
+            }
+            (PgoStage::Generate(dir), CompilerFamily::Clang) => {
+                let file = cwd.join(dir).join("luajit-%p.profraw");
+                format!("-fprofile-instr-generate={}", file.display())
+            }
+            (PgoStage::Use(dir), CompilerFamily::Clang) => {
+                let file = cwd.join(dir).join("luajit.profdata");
+                format!("-fprofile-instr-use={}", file.display())
+            }
+            _ => {
+                let msg = "PGO builds require GCC or Clang";
+                return Err(Error::Unsupported(msg.to_string()));
+            }
+        };
+        Ok(vec![flag])
+    }
+
+    // Defines applied the same way to the unix and MSVC builds
+    fn common_defines(&self) -> Vec<String> {
+        let mut defines = Vec::new();
+        if self.options.assertions {
+            defines.push("LUA_USE_ASSERT".to_string());
+            defines.push("LUA_USE_APICHECK".to_string());
+        }
+        match self.options.gc64 {
+            Some(true) => defines.push("LUAJIT_ENABLE_GC64".to_string()),
+            Some(false) => defines.push("LUAJIT_DISABLE_GC64".to_string()),
+            None => {}
+        }
+        if self.options.disable_ffi {
+            defines.push("LUAJIT_DISABLE_FFI".to_string());
+        }
+        if let Some(enabled) = self.options.security_prng {
+            defines.push(format!("LUAJIT_SECURITY_PRNG={}", enabled as u8));
+        }
+        if let Some(enabled) = self.options.security_strhash {
+            defines.push(format!("LUAJIT_SECURITY_STRHASH={}", enabled as u8));
+        }
+        let bare_metal = self.options.target_os == Some(TargetOs::Other);
+        if self.jit_disabled() {
+            defines.push("LUAJIT_DISABLE_JIT".to_string());
+        } else if self.options.disable_jit == Some(false) {
+            // Overrides `LJ_OS_NOJIT` of the iOS and console targets
+            defines.push("LUAJIT_ENABLE_JIT".to_string());
+        }
+        if bare_metal {
+            defines.push("LUAJIT_NO_UNWIND".to_string());
+        }
+        if bare_metal || self.options.allocator != Allocator::Internal {
+            defines.push("LUAJIT_USE_SYSMALLOC".to_string());
+        }
+        defines
+    }
+
+    // Whether the JIT compiler is left out, not counting `runtime_jit_probe`
+    fn jit_disabled(&self) -> bool {
+        if self.options.target_os == Some(TargetOs::Other) {
+            return true;
+        }
+        if let Some(disabled) = self.options.disable_jit {
+            return disabled;
+        }
+        let target = self.target.as_deref().unwrap_or_default();
+        WX_TARGETS.iter().any(|os| target.contains(os))
+    }
+
+    // Runs `JIT_PROBE` built with `compiler`, false if RX memory is refused
+    fn probe_jit(&self, compiler: &str, args: &str, out_dir: &Path) -> Result<bool, Error> {
+        let src = out_dir.join("jit_probe.c");
+        let exe = out_dir.join("jit_probe");
+        fs::write(&src, JIT_PROBE).context(|| format!("cannot write {}", src.display()))?;
+        let mut cc = Command::new(compiler);
+        cc.args(args.split_whitespace())
+            .arg("-o")
+            .arg(&exe)
+            .arg(&src);
+        self.run_command(&mut cc, "building the JIT probe")?;
+        let allowed = Command::new(&exe)
+            .status()
+            .is_ok_and(|status| status.success());
+        let _ = fs::remove_file(&exe);
+        Ok(allowed)
+    }
+
+    // Runs the main build, which must produce `lib`. With `require_lib_only` a
+    // failing build is fine as long as it did.
+    fn run_build(&self, command: &mut Command, desc: &str, lib: &Path) -> Result<(), Error> {
+        if let Some(path) = &self.options.build_script {
+            self.write_build_script_to(path, command)?;
+        }
+        let started = std::time::SystemTime::now();
+        let err = match self.run_command(command, desc) {
+            Err(
+                err @ Error::CommandFailed {
+                    status: Some(_), ..
+                },
+            ) => err,
+            Ok(()) if !lib.is_file() => return Err(Error::LibraryNotProduced(lib.to_path_buf())),
+            result => return result,
+        };
+        // An incremental build may have left an older library behind
+        let built = fs::metadata(lib)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|mtime| mtime >= started);
+        if !self.options.require_lib_only || !built {
+            return Err(err);
+        }
+        self.warn(format!(
+            "{desc} failed, continuing since {} was built",
+            lib.file_name().unwrap().to_string_lossy()
+        ));
+        Ok(())
+    }
+
+    fn write_build_script_to(&self, path: &Path, command: &Command) -> Result<(), Error> {
+        let host = self.host.as_ref().ok_or(Error::MissingSetting("HOST"))?;
+        let batch = host.contains("windows");
+        let quote = |value: &OsStr| {
+            let value = value.to_string_lossy();
+            if batch {
+                format!("\"{value}\"")
+            } else {
+                format!("'{}'", value.replace('\'', r"'\''"))
+            }
+        };
+
+        let mut lines = if batch {
+            vec![
+                "@echo off".to_string(),
+                "rem LuaJIT build written by luajit-src".to_string(),
+            ]
+        } else {
+            let header = [
+                "#!/bin/sh",
+                "# LuaJIT build written by luajit-src",
+                "set -e",
+            ];
+            header.map(String::from).to_vec()
+        };
+        if let Some(dir) = command.get_current_dir() {
+            let cd = if batch { "cd /d" } else { "cd" };
+            lines.push(format!("{cd} {}", quote(dir.as_os_str())));
+        }
+        for (key, value) in command.get_envs() {
+            let key = key.to_string_lossy();
+            lines.push(match value {
+                Some(value) if batch => format!("set \"{key}={}\"", value.to_string_lossy()),
+                Some(value) => format!("export {key}={}", quote(value)),
+                None if batch => format!("set \"{key}=\""),
+                None => format!("unset {key}"),
+            });
+        }
+        let invocation = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(quote)
+            .collect::<Vec<_>>();
+        lines.push(invocation.join(" "));
+
+        let newline = if batch { "\r\n" } else { "\n" };
+        let script = lines.join(newline) + newline;
+        fs::write(path, script).context(|| format!("cannot write {}", path.display()))
+    }
+
+    // Copies the static library to `dst`. Thin archives store member paths
+    // relative to themselves, so they are recreated instead using `ar`, the
+    // `TARGET_AR` command minus its trailing operation flags.
+    fn install_archive(&self, ar: &OsStr, src: &Path, dst: &Path) -> Result<(), Error> {
+        let desc = || format!("cannot install {}", dst.display());
+        let mut magic = [0; 8];
+        let is_thin = fs::File::open(src)
+            .and_then(|mut file| io::Read::read_exact(&mut file, &mut magic))
+            .is_ok_and(|_| &magic == b"!<thin>\n");
+        if !is_thin {
+            return copy_file(src, dst).context(desc);
+        }
+
+        let members = archive_members(ar, src)?;
+        let ar = ar.to_str().unwrap().split_whitespace().collect::<Vec<_>>();
+        let (program, args) = ar[..ar.len() - 1].split_first().unwrap();
+        if dst.exists() {
+            fs::remove_file(dst).context(desc)?;
+        }
+        let mut command = Command::new(program);
+        command.args(args).arg("rcsT").arg(dst).args(&members);
+        self.run_command(&mut command, "installing LuaJIT thin archive")
+    }
+
+    fn index_archive(&self, ranlib: Option<&OsStr>, archive: &Path) -> Result<(), Error> {
+        if let Some(ranlib) = ranlib {
+            let mut ranlib = Command::new(ranlib);
+            ranlib.arg(archive);
+            self.run_command(&mut ranlib, "indexing LuaJIT archive")?;
+        }
+        let has_index =
+            has_archive_index(archive).context(|| format!("cannot read {}", archive.display()))?;
+        if !has_index {
+            return Err(Error::MissingArchiveIndex(archive.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    fn finish_timings(&self, timings: Timings) -> Option<Timings> {
+        if !self.options.report_timings {
+            return None;
+        }
+        timings.report();
+        Some(timings)
+    }
+
+    // Stores the files `make` added to `src`, and the link map, as a cache entry.
+    // It is staged next to the entry so other builds never see it half written.
+    fn store_cached(
+        &self,
+        entry: &Path,
+        source_dir: &Path,
+        build_dir: &Path,
+        link_map: Option<&Path>,
+    ) -> Result<(), Error> {
+        let mut sources = Vec::new();
+        list_files(&source_dir.join("src"), Path::new(""), &mut sources)?;
+        let mut built = Vec::new();
+        list_files(&build_dir.join("src"), Path::new(""), &mut built)?;
+        let staging = entry.with_extension(format!("{}.tmp", std::process::id()));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)
+                .context(|| format!("cannot remove {}", staging.display()))?;
+        }
+        for file in built.iter().filter(|file| !sources.contains(file)) {
+            let (src, dst) = (
+                build_dir.join("src").join(file),
+                staging.join("src").join(file),
+            );
+            let dir = dst.parent().unwrap();
+            fs::create_dir_all(dir).context(|| format!("cannot create {}", dir.display()))?;
+            copy_file(&src, &dst).context(|| format!("cannot cache {}", src.display()))?;
+        }
+        if let Some(map) = link_map {
+            copy_file(map, &staging.join("luajit.map"))
+                .context(|| format!("cannot cache {}", map.display()))?;
+        }
+        // Another build may have stored the same entry in the meantime
+        if fs::rename(&staging, entry).is_err() {
+            let _ = fs::remove_dir_all(&staging);
+        }
+        Ok(())
+    }
+
+    fn restore_cached(
+        &self,
+        entry: &Path,
+        build_dir: &Path,
+        link_map: Option<&Path>,
+    ) -> Result<(), Error> {
+        cp_r(&entry.join("src"), &build_dir.join("src"))?;
+        if let Some(map) = link_map {
+            let cached = entry.join("luajit.map");
+            copy_file(&cached, map).context(|| format!("cannot restore {}", map.display()))?;
+        }
+        Ok(())
+    }
+
+    // Runs `luajit` from the build directory, next to the shared library if any,
+    // returning the version it printed
+    fn run_self_check(&self, luajit: &Path) -> Result<String, Error> {
+        let src_dir = luajit.parent().unwrap();
+        let mut command = Command::new(luajit);
+        command
+            .args([
+                "-e",
+                "assert(1 + 1 == 2) print(jit and jit.version or _VERSION)",
+            ])
+            .env("LD_LIBRARY_PATH", prepend_path("LD_LIBRARY_PATH", src_dir))
+            .env(
+                "DYLD_LIBRARY_PATH",
+                prepend_path("DYLD_LIBRARY_PATH", src_dir),
+            );
+        println!("running {command:?}");
+        let output = command.output().ok();
+        let stdout = output
+            .as_ref()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .unwrap_or_default();
+        let works = output
+            .as_ref()
+            .is_some_and(|output| output.status.success())
+            && (stdout.starts_with("LuaJIT 2.") || stdout == "Lua 5.1");
+        if !works {
+            return Err(Error::CommandFailed {
+                desc: format!("self-checking the LuaJIT interpreter (printed {stdout:?})"),
+                command: format!("{command:?}"),
+                status: output.map(|output| output.status),
+            });
+        }
+        Ok(stdout)
+    }
+
+    // Advisory message about the configuration, see `Artifacts::warnings`
+    fn warn(&self, msg: String) {
+        println!("cargo:warning={msg}");
+        self.warnings.borrow_mut().push(msg);
+    }
+
+    fn run_command(&self, command: &mut Command, desc: &str) -> Result<(), Error> {
+        println!("running {:?}", command);
+        let status = match &mut *self.on_output.borrow_mut() {
+            Some(callback) => run_piped(command, callback),
+            None => command.status().ok(),
+        };
+        if !matches!(status, Some(status) if status.success()) {
+            return Err(Error::CommandFailed {
+                desc: desc.to_string(),
+                command: format!("{command:?}"),
+                status,
+            });
+        }
+        Ok(())
+    }
+}
+
+// `Command` is not `Clone`, rebuild one with the same program, arguments and environment
+fn clone_command(command: &Command) -> Command {
+    let mut clone = Command::new(command.get_program());
+    clone.args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => clone.env(key, value),
+            None => clone.env_remove(key),
+        };
+    }
+    if let Some(dir) = command.get_current_dir() {
+        clone.current_dir(dir);
+    }
+    clone
+}
+
+fn compiler_version(compiler: &Path, flag: &str) -> Option<String> {
+    let output = Command::new(compiler).arg(flag).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().next().map(|line| line.trim().to_string())
+}
+
+// Infer ar/ranlib tools from cross compilers if the it looks like
+// we're doing something like `foo-gcc` route that to `foo-ranlib`
+// as well.
+fn tool_prefix(compiler_path: &str) -> &str {
+    if compiler_path.ends_with("-gcc") {
+        &compiler_path[..compiler_path.len() - 3]
+    } else if compiler_path.ends_with("-clang") {
+        &compiler_path[..compiler_path.len() - 5]
+    } else {
+        ""
+    }
+}
+
+fn link_map_flag(target: &str, map: &Path) -> String {
+    if target.contains("apple") {
+        format!("-Wl,-map,{}", map.display())
+    } else {
+        format!("-Wl,-Map={}", map.display())
+    }
+}
+
+fn make_program(host: &str) -> &'static str {
+    match host {
+        "x86_64-unknown-dragonfly" => "gmake",
+        "x86_64-unknown-freebsd" => "gmake",
+        _ => "make",
+    }
+}
+
+fn is_zig(compiler: &cc::Tool) -> bool {
+    compiler.path().file_stem() == Some(OsStr::new("zig"))
+        && compiler.args().first().is_some_and(|arg| arg == "cc")
+}
+
+// Sysroot the compiler was configured with, `None` if it has none or cannot tell
+fn compiler_sysroot(compiler: &Path, args: &[OsString]) -> Option<PathBuf> {
+    let output = Command::new(compiler)
+        .args(args)
+        .arg("-print-sysroot")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!sysroot.is_empty()).then(|| PathBuf::from(sysroot))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Control-flow and stack clash protection flags for `target`
+fn hardening_flags(target: &str) -> Vec<&'static str> {
+    let mut flags = Vec::new();
+    let arch = target.split('-').next().unwrap_or_default();
+    if matches!(arch, "x86_64" | "i586" | "i686") {
+        flags.push("-fcf-protection=full");
+    } else if arch == "aarch64" {
+        flags.push("-mbranch-protection=standard");
+    }
+    if !target.contains("apple") && !target.contains("windows") {
+        flags.push("-fstack-clash-protection");
+    }
+    flags
+}
+
+// Checks a `pkg-config --modversion` like `2.1.1713484068`, the relver being the
+// rolling release timestamp
+fn check_relver(found: String, required: u32) -> Result<(), Error> {
+    let relver = found.rsplit('.').next().and_then(|v| v.parse::<u32>().ok());
+    if !matches!(relver, Some(relver) if relver >= required) {
+        return Err(Error::VersionTooOld { found, required });
+    }
+    Ok(())
+}
+
+// Appends `flag` to the `var` passed to make, on top of the environment's value
+fn append_make_flag(make: &mut Command, var: &str, flag: &str) {
+    let existing = make
+        .get_envs()
+        .find(|(name, _)| *name == var)
+        .and_then(|(_, value)| value.map(|v| v.to_os_string()))
+        .or_else(|| env::var_os(var));
+    let flags = match existing {
+        Some(existing) => format!("{} {flag}", existing.to_string_lossy()),
+        None => flag.to_string(),
+    };
+    make.env(var, flags);
+}
+
+// `TARGET_SYS` (the `uname -s` spelling the makefile expects) for a target triple
+fn target_sys(target: &str) -> Option<&'static str> {
+    let target_sys = match target {
+        _ if target.contains("linux") || target.contains("android") => "Linux",
+        _ if target.contains("windows") => "Windows",
+        _ if target.contains("apple-darwin") => "Darwin",
+        _ if target.contains("apple") => "iOS",
+        _ if target.contains("freebsd") => "FreeBSD",
+        _ if target.contains("netbsd") => "NetBSD",
+        _ if target.contains("openbsd") => "OpenBSD",
+        _ if target.contains("dragonfly") => "DragonFly",
+        _ if target.contains("solaris") || target.contains("illumos") => "SunOS",
+        _ if target.contains("haiku") => "Haiku",
+        _ => return None,
+    };
+    Some(target_sys)
+}
+
+// Converts a Rust target triple to the `arch-os-abi` form `zig cc -target` expects
+fn zig_target(target: &str) -> String {
+    let mut parts = target.split('-').collect::<Vec<_>>();
+    if parts.len() == 4 || (parts.len() == 3 && matches!(parts[1], "unknown" | "pc" | "apple")) {
+        parts.remove(1);
+    }
+    parts[0] = match parts[0] {
+        "i586" | "i686" => "x86",
+        arch if arch.starts_with("armv7") || arch.starts_with("thumbv7") => "arm",
+        arch if arch.starts_with("riscv64") => "riscv64",
+        arch => arch,
+    };
+    if parts.get(1) == Some(&"darwin") {
+        parts[1] = "macos";
+    }
+    parts.join("-")
+}
+
+// Deployment target variable and its default for Apple targets
+fn apple_deployment_target(target: &str) -> Option<(&'static str, &'static str)> {
+    if !target.contains("-apple-") {
+        return None;
+    }
+    let is_arm64 = target.starts_with("aarch64") || target.starts_with("arm64");
+    Some(if target.contains("-darwin") {
+        let default = if is_arm64 { "11.0" } else { "10.11" };
+        ("MACOSX_DEPLOYMENT_TARGET", default)
+    } else if target.ends_with("-macabi") {
+        let default = if is_arm64 { "14.0" } else { "13.1" };
+        ("IPHONEOS_DEPLOYMENT_TARGET", default)
+    } else if target.contains("-ios") {
+        ("IPHONEOS_DEPLOYMENT_TARGET", "10.0")
+    } else if target.contains("-tvos") {
+        ("TVOS_DEPLOYMENT_TARGET", "10.0")
+    } else if target.contains("-watchos") {
+        ("WATCHOS_DEPLOYMENT_TARGET", "5.0")
+    } else if target.contains("-visionos") {
+        ("XROS_DEPLOYMENT_TARGET", "1.0")
+    } else {
+        return None;
+    })
+}
+
+// Runs `command` forwarding each stdout/stderr line to `callback`
+fn run_piped(command: &mut Command, callback: &mut dyn FnMut(&str)) -> Option<ExitStatus> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let readers = [
+        thread::spawn({
+            let tx = tx.clone();
+            move || forward_lines(stdout, tx)
+        }),
+        thread::spawn(move || forward_lines(stderr, tx)),
+    ];
+    for line in rx {
+        println!("{line}");
+        callback(&line);
+    }
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    child.wait().ok()
+}
+
+fn forward_lines<R: io::Read>(reader: R, tx: mpsc::Sender<String>) {
+    for line in BufReader::new(reader).lines().map_while(Result::ok) {
+        if tx.send(line).is_err() {
+            break;
+        }
+    }
+}
+
+fn default_out_dir(target_dir: Option<PathBuf>, target: &str) -> PathBuf {
+    target_dir
+        .unwrap_or_else(env::temp_dir)
+        .join("luajit-build")
+        .join(target)
+}
+
+// Replaces the first occurrence of `from` in `src_dir/file`, failing if the
+// vendored sources no longer contain it.
+fn patch_source(src_dir: &Path, file: &str, from: &str, to: &str) -> Result<(), Error> {
+    let path = src_dir.join(file);
+    let contents = fs::read_to_string(&path).context(|| format!("cannot read {file}"))?;
+    if !contents.contains(from) {
+        return Err(Error::Unsupported(format!(
+            "cannot patch {file}: `{from}` not found in LuaJIT sources"
+        )));
+    }
+    fs::write(&path, contents.replacen(from, to, 1)).context(|| format!("cannot write {file}"))
+}
+
+// Replaces the value of `#define name ...` in `src_dir/file`
+fn patch_define(src_dir: &Path, file: &str, name: &str, value: &str) -> Result<(), Error> {
+    let path = src_dir.join(file);
+    let contents = fs::read_to_string(&path).context(|| format!("cannot read {file}"))?;
+    let mut found = false;
+    let patched = contents
+        .lines()
+        .map(|line| {
+            let mut words = line.split_whitespace();
+            if !found && words.next() == Some("#define") && words.next() == Some(name) {
+                found = true;
+                format!("#define {name}\t{value}")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    if !found {
+        return Err(Error::Unsupported(format!(
+            "cannot patch {file}: `{name}` is not defined in LuaJIT sources"
+        )));
+    }
+    fs::write(&path, patched.join("\n") + "\n").context(|| format!("cannot write {file}"))
+}
+
+// Quotes `s` as a C string literal. Anything but printable ASCII is written as
+// an octal escape, which unlike `\x` cannot swallow the following characters.
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            // Avoid forming trigraphs
+            b'?' => out.push_str("\\?"),
+            b' '..=b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("\\{byte:03o}")),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn install_headers(build_dir: &Path, include_dir: &Path) -> Result<(), Error> {
+    for f in PUBLIC_HEADERS {
+        copy_file(&build_dir.join("src").join(f), &include_dir.join(f))
+            .context(|| format!("cannot install {f}"))?;
+    }
+    Ok(())
+}
+
+fn install_tests(
+    source_dir: &Path,
+    executable: &Path,
+    out_dir: &Path,
+) -> Result<(Option<PathBuf>, Option<PathBuf>), Error> {
+    let bin_dir = out_dir.join("bin");
+    fs::create_dir_all(&bin_dir).context(|| format!("cannot create {}", bin_dir.display()))?;
+    let installed = bin_dir.join(executable.file_name().unwrap());
+    copy_file(executable, &installed)
+        .context(|| format!("cannot copy {}", executable.display()))?;
+
+    // The test suite is not part of every LuaJIT distribution
+    let test_dir = out_dir.join("test");
+    if test_dir.exists() {
+        fs::remove_dir_all(&test_dir)
+            .context(|| format!("cannot remove {}", test_dir.display()))?;
+    }
+    let test_dir = if source_dir.join("test").is_dir() {
+        fs::create_dir_all(&test_dir)
+            .context(|| format!("cannot create {}", test_dir.display()))?;
+        cp_r(&source_dir.join("test"), &test_dir)?;
+        Some(test_dir)
+    } else {
+        None
+    };
+
+    Ok((Some(installed), test_dir))
+}
+
+// Collects the files under `dir` relative to it, skipping git metadata like `cp_r`
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let path = root.join(dir);
+    let entries = fs::read_dir(&path).context(|| format!("cannot read {}", path.display()))?;
+    for entry in entries {
+        let entry = entry.context(|| format!("cannot read {}", path.display()))?;
+        let name = entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let file_type = entry
+            .file_type()
+            .context(|| format!("cannot stat {}", entry.path().display()))?;
+        if file_type.is_dir() {
+            list_files(root, &dir.join(&name), files)?;
+        } else {
+            files.push(dir.join(&name));
+        }
+    }
+    Ok(())
+}
+
+fn file_stamp(path: &Path, name: &Path) -> Result<String, Error> {
+    let metadata = fs::metadata(path).context(|| format!("cannot stat {}", path.display()))?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_nanos());
+    Ok(format!("{}\t{}\t{mtime}\n", name.display(), metadata.len()))
+}
+
+// Name of the cache entry for a build, from its stamp and the `.relver` it was
+// configured with. `DefaultHasher` is only stable within a Rust release, which
+// merely costs a rebuild after an upgrade.
+fn cache_key(stamp: &str, build_dir: &Path) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    stamp.hash(&mut hasher);
+    fs::read(build_dir.join(".relver")).ok().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn cp_r(src: &Path, dst: &Path) -> Result<(), Error> {
+    let entries = fs::read_dir(src).context(|| format!("cannot read {}", src.display()))?;
+    for f in entries {
+        let f = f.context(|| format!("cannot read {}", src.display()))?;
+        let path = f.path();
+        let name = path.file_name().unwrap();
+
+        // Skip git metadata
+        if name.to_str() == Some(".git") {
+            continue;
+        }
+
+        let dst = dst.join(name);
+        let file_type = f
+            .file_type()
+            .context(|| format!("cannot stat {}", path.display()))?;
+        if file_type.is_dir() {
+            fs::create_dir_all(&dst).context(|| format!("cannot create {}", dst.display()))?;
+            cp_r(&path, &dst)?;
+        } else {
+            let _ = fs::remove_file(&dst);
+            copy_file(&path, &dst)
+                .context(|| format!("cannot copy {} to {}", path.display(), dst.display()))?;
+        }
+    }
+    Ok(())
+}
+
+// Members of `archive` as paths next to it, listed with `ar`, the `TARGET_AR`
+// command minus its trailing operation flags
+fn archive_members(ar: &OsStr, archive: &Path) -> Result<Vec<PathBuf>, Error> {
+    let ar = ar.to_str().unwrap().split_whitespace().collect::<Vec<_>>();
+    let (program, args) = ar[..ar.len() - 1].split_first().unwrap();
+    let output = Command::new(program)
+        .args(args)
+        .arg("t")
+        .arg(archive)
+        .output()
+        .context(|| format!("cannot list {}", archive.display()))?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            desc: "listing archive members".to_string(),
+            command: format!("{program} t {}", archive.display()),
+            status: Some(output.status),
+        });
+    }
+    let dir = archive.parent().unwrap();
+    let members = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|member| dir.join(member))
+        .collect();
+    Ok(members)
+}
+
+// Undefined glibc symbols of `binary` as `name@GLIBC_x.y`, from `objdump -T`
+fn glibc_symbols(objdump: &Path, binary: &Path) -> Vec<String> {
+    let Ok(output) = Command::new(objdump).arg("-T").arg(binary).output() else {
+        return Vec::new();
+    };
+    let mut symbols = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.contains("*UND*"))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().rev();
+            let name = fields.next()?;
+            let version = fields.next()?.trim_matches(|c| c == '(' || c == ')');
+            version
+                .starts_with("GLIBC_2")
+                .then(|| format!("{name}@{version}"))
+        })
+        .collect::<Vec<_>>();
+    symbols.sort();
+    symbols.dedup();
+    symbols
+}
+
+// `2.2.5` as `[2, 2, 5]`, for comparing versions
+fn version_key(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+// Whether the first member of an ar archive is its symbol index
+fn has_archive_index(path: &Path) -> io::Result<bool> {
+    let mut header = Vec::new();
+    let file = fs::File::open(path)?;
+    io::Read::read_to_end(&mut io::Read::take(file, 84), &mut header)?;
+    if header.len() < 68 || !matches!(&header[..8], b"!<arch>\n" | b"!<thin>\n") {
+        return Ok(false);
+    }
+    // BSD archives store long member names right after the member header
+    let name = match &header[8..24] {
+        name if name.starts_with(b"#1/") => &header[68..],
+        name => name,
+    };
+    Ok(name.starts_with(b"/ ") || name.starts_with(b"/SYM64/") || name.starts_with(b"__.SYMDEF"))
+}
+
+// `fs::copy` also transfers permissions, which some overlay/tmpfs mounts reject when
+// crossing filesystems. Retry by copying just the contents in that case.
+fn copy_file(src: &Path, dst: &Path) -> io::Result<()> {
+    match fs::copy(src, dst) {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let _ = fs::remove_file(dst);
+            let mut reader = fs::File::open(src)?;
+            let mut writer = fs::File::create(dst)
+                .map_err(|e| io::Error::new(e.kind(), format!("{e} (after: {err})")))?;
+            io::copy(&mut reader, &mut writer)?;
+            Ok(())
+        }
+    }
+}
+
+// `dir` followed by the search path inherited in `var`
+fn prepend_path(var: &str, dir: &Path) -> OsString {
+    let inherited = env::var_os(var).unwrap_or_default();
+    let paths = env::split_paths(&inherited).filter(|path| !path.as_os_str().is_empty());
+    env::join_paths(std::iter::once(dir.to_path_buf()).chain(paths))
+        .unwrap_or_else(|_| dir.as_os_str().to_os_string())
+}
+
+impl Artifacts {
+    pub fn include_dir(&self) -> &Path {
+        &self.include_dir
+    }
+
+    pub fn lib_dir(&self) -> &Path {
+        &self.lib_dir
+    }
+
+    pub fn libs(&self) -> &[Lib] {
+        &self.libs
+    }
+
+    /// Returns the arguments printed as `cargo:rustc-link-arg` by
+    /// [`Artifacts::print_cargo_metadata`], e.g. for [`Build::rpath`].
+    pub fn link_args(&self) -> &[String] {
+        &self.link_args
+    }
+
+    /// Adds a library to link along with LuaJIT, e.g. a companion library
+    /// installed into [`Artifacts::lib_dir`].
+    pub fn add_lib(&mut self, name: &str, kind: LinkKind) -> &mut Artifacts {
+        self.libs.push(Lib::new(name, kind));
+        self
+    }
+
+    /// Returns the static libraries in `cargo:rustc-link-lib` form for plain
+    /// static linking, empty for [`Build::shared`] and system builds.
+    pub fn static_libs(&self) -> Vec<String> {
+        self.link_libs("static")
+    }
+
+    /// Same as [`Artifacts::static_libs`], but with the `+whole-archive` modifier.
+    pub fn whole_archive_libs(&self) -> Vec<String> {
+        self.link_libs("static:+whole-archive")
+    }
+
+    fn link_libs(&self, kind: &str) -> Vec<String> {
+        self.libs
+            .iter()
+            .filter(|lib| lib.kind == LinkKind::Static)
+            .map(|lib| format!("{kind}={}", lib.name))
+            .collect()
+    }
+
+    /// Combines per-architecture builds into one, creating a fat library with
+    /// `lipo` on Apple targets.
+    ///
+    /// All artifacts must have identical headers. The merged library is written to
+    /// `lib-universal` next to this artifact's `lib_dir`. On other platforms only
+    /// identical libraries can be merged since there is no fat library format.
+    pub fn merge(&self, others: &[Artifacts]) -> Result<Artifacts, Error> {
+        for other in others {
+            if other.libs != self.libs || other.shared_lib.is_some() != self.shared_lib.is_some() {
+                return Err(Error::Incompatible("different libraries".to_string()));
+            }
+            for header in PUBLIC_HEADERS {
+                let read = |dir: &Path| {
+                    let path = dir.join(header);
+                    fs::read(&path).context(|| format!("cannot read {}", path.display()))
+                };
+                if read(&self.include_dir)? != read(&other.include_dir)? {
+                    let msg = format!(
+                        "{header} differs between {} and {}",
+                        self.target, other.target
+                    );
+                    return Err(Error::Incompatible(msg));
+                }
+            }
+        }
+
+        let lib_dir = self.lib_dir.with_file_name("lib-universal");
+        if lib_dir.exists() {
+            fs::remove_dir_all(&lib_dir)
+                .context(|| format!("cannot remove {}", lib_dir.display()))?;
+        }
+        fs::create_dir_all(&lib_dir).context(|| format!("cannot create {}", lib_dir.display()))?;
+
+        let mut merged = self.clone();
+        for entry in fs::read_dir(&self.lib_dir)
+            .context(|| format!("cannot read {}", self.lib_dir.display()))?
+        {
+            let entry = entry.context(|| format!("cannot read {}", self.lib_dir.display()))?;
+            let name = entry.file_name();
+            // Only the libraries themselves, not linker scripts or symbol lists
+            let file_name = name.to_string_lossy();
+            let is_lib = self.libs.iter().any(|lib| {
+                file_name.starts_with(&format!("lib{}.", lib.name))
+                    || file_name.starts_with(&format!("{}.", lib.name))
+            });
+            if !is_lib || !entry.path().is_file() {
+                continue;
+            }
+            let inputs = std::iter::once(self)
+                .chain(others)
+                .map(|a| a.lib_dir.join(&name))
+                .collect::<Vec<_>>();
+            let contents = inputs
+                .iter()
+                .map(|path| fs::read(path).context(|| format!("cannot read {}", path.display())))
+                .collect::<Result<Vec<_>, _>>()?;
+            let output = lib_dir.join(&name);
+
+            if contents.iter().all(|c| *c == contents[0]) {
+                copy_file(&inputs[0], &output)
+                    .context(|| format!("cannot write {}", output.display()))?;
+            } else if self.target.contains("apple") {
+                let mut lipo = Command::new("lipo");
+                lipo.arg("-create")
+                    .args(&inputs)
+                    .arg("-output")
+                    .arg(&output);
+                let status = lipo.status().ok();
+                if !matches!(status, Some(status) if status.success()) {
+                    return Err(Error::CommandFailed {
+                        desc: "merging libraries".to_string(),
+                        command: format!("{lipo:?}"),
+                        status,
+                    });
+                }
+            } else {
+                let msg = format!(
+                    "{} differs between architectures and {} has no fat libraries",
+                    name.to_string_lossy(),
+                    self.target
+                );
+                return Err(Error::Incompatible(msg));
+            }
+        }
+
+        if let Some(shared_lib) = &self.shared_lib {
+            merged.shared_lib = Some(lib_dir.join(shared_lib.file_name().unwrap()));
+        }
+        merged.lib_dir = lib_dir;
+        Ok(merged)
+    }
+
+    /// Returns the host tools, if built with [`Build::build_host_tools_only`].
+    pub fn host_tools(&self) -> Option<&HostTools> {
+        self.host_tools.as_ref()
+    }
+
+    /// Returns the toolchain and flags used to build LuaJIT.
+    pub fn build_info(&self) -> &BuildInfo {
+        &self.build_info
+    }
+
+    /// Returns the family of the C compiler LuaJIT was built with.
+    pub fn compiler_family(&self) -> CompilerFamily {
+        self.build_info.compiler_family
+    }
+
+    /// Writes [`Artifacts::build_info`] to `path` as JSON. Returns the written
+    /// path.
+    pub fn write_build_info<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        fs::write(path, self.build_info.to_json())
+            .context(|| format!("cannot write {}", path.display()))?;
+        Ok(path.to_path_buf())
+    }
+
+    /// Writes a header to `path` for plugins importing the LuaJIT API from the
+    /// shared library of a host, to be included instead of `lua.h` and friends.
+    /// On Windows it declares the API `__declspec(dllimport)` by defining
+    /// `LUA_BUILD_AS_DLL`.
+    ///
+    /// Only supported for shared builds. The header includes the public LuaJIT
+    /// headers, so [`Artifacts::include_dir`] must be on the include path.
+    /// Returns the written path.
+    pub fn write_import_header<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        if self.shared_lib.is_none() {
+            return Err(Error::InvalidOption(
+                "import headers require a shared build".to_string(),
+            ));
+        }
+        let mut header = String::from(
+            "/* LuaJIT API imported from a shared library, written by luajit-src */\n\
+             #ifndef LUAJIT_IMPORT_H\n\
+             #define LUAJIT_IMPORT_H\n\n",
+        );
+        if self.target.contains("windows") {
+            header.push_str("#ifndef LUA_BUILD_AS_DLL\n#define LUA_BUILD_AS_DLL\n#endif\n\n");
+        }
+        for f in &PUBLIC_HEADERS[1..] {
+            header.push_str(&format!("#include \"{f}\"\n"));
+        }
+        header.push_str("\n#endif\n");
+        fs::write(path, header).context(|| format!("cannot write {}", path.display()))?;
+        Ok(path.to_path_buf())
+    }
+
+    /// Writes the sorted list of public LuaJIT symbols (`lua_*`, `luaL_*`,
+    /// `luaJIT_*` and `luaopen_*`) defined by the built library to `path`, one
+    /// per line, e.g. for diffing the exported API between builds.
+    ///
+    /// Uses `nm` next to the archiver, or `dumpbin` for MSVC builds. Returns the
+    /// written path.
+    pub fn write_symbols<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        let lib = match (&self.shared_lib, self.libs.first()) {
+            (Some(shared_lib), _) => shared_lib.clone(),
+            (None, Some(lib)) if self.target.contains("msvc") => {
+                self.lib_dir.join(format!("{}.lib", lib.name))
+            }
+            (None, Some(lib)) => self.lib_dir.join(format!("lib{}.a", lib.name)),
+            (None, None) => return Err(Error::InvalidOption("no library to read".to_string())),
+        };
+        if !lib.is_file() {
+            return Err(Error::InvalidOption(format!("{} not found", lib.display())));
+        }
+
+        let (mut cmd, name) = if self.target.contains("msvc") {
+            let mut cmd = cc::windows_registry::find(&self.target, "dumpbin.exe")
+                .ok_or_else(|| Error::ToolNotFound("dumpbin.exe".to_string()))?;
+            cmd.arg("/nologo");
+            match self.shared_lib {
+                Some(_) => cmd.arg("/exports"),
+                None => cmd.arg("/symbols"),
+            };
+            (cmd, "dumpbin")
+        } else {
+            // `x86_64-linux-gnu-ar` pairs with `x86_64-linux-gnu-nm`, `llvm-ar`
+            // with `llvm-nm` and so on
+            let nm = self
+                .build_info
+                .ar
+                .as_deref()
+                .and_then(|ar| ar.split_whitespace().next()?.strip_suffix("ar"))
+                .map(|prefix| format!("{prefix}nm"))
+                .filter(|nm| which::which(nm).is_ok())
+                .unwrap_or_else(|| "nm".to_string());
+            let mut cmd = Command::new(nm);
+            cmd.args(["-g", "-P", "--defined-only"]);
+            (cmd, "nm")
+        };
+        cmd.arg(&lib);
+        let output = cmd
+            .output()
+            .map_err(|_| Error::ToolNotFound(name.to_string()))?;
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                desc: format!("listing the symbols of {}", lib.display()),
+                command: format!("{cmd:?}"),
+                status: Some(output.status),
+            });
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let names = stdout.lines().filter_map(|line| {
+            if self.target.contains("msvc") {
+                // `008 00000000 SECT3  notype ()    External     | lua_pcall` for
+                // archives, `    1    0 00001234 lua_pcall` for exports
+                match self.shared_lib {
+                    Some(_) => line.split_whitespace().nth(3),
+                    None if line.contains("External") && !line.contains("UNDEF") => {
+                        line.split('|').nth(1)?.split_whitespace().next()
+                    }
+                    None => None,
+                }
+            } else {
+                // `lua_pcall T 0000000000001234 0000000000000056`
+                line.split_whitespace().next()
+            }
+        });
+        // Mach-O and 32-bit Windows decorate C symbols with a leading underscore
+        let decorated = self.target.contains("apple")
+            || (self.target.starts_with("i686") && self.target.contains("windows"));
+        let mut symbols = names
+            .map(|name| match decorated {
+                true => name.strip_prefix('_').unwrap_or(name),
+                false => name,
+            })
+            .filter(|name| {
+                ["lua_", "luaL_", "luaJIT_", "luaopen_"]
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_unstable();
+        symbols.dedup();
+
+        let mut contents = symbols.join("\n");
+        contents.push('\n');
+        fs::write(path, contents).context(|| format!("cannot write {}", path.display()))?;
+        Ok(path.to_path_buf())
+    }
+
+    /// Packages the build into an archive at `path` laid out as an installation
+    /// prefix, for handing LuaJIT to non-Cargo projects:
+    ///
+    /// - `include/luajit-2.1/`: the public headers
+    /// - `lib/`: the libraries, and `lib/pkgconfig/luajit.pc` referring to them
+    ///   relative to itself
+    /// - `share/luajit-2.1/jit/`: the `jit.*` Lua modules
+    ///
+    /// Requires `tar`, or `zip` for [`ArchiveFormat::Zip`] on non-Windows hosts.
+    /// Returns the path of the written archive.
+    pub fn write_archive(&self, path: &Path, format: ArchiveFormat) -> Result<PathBuf, Error> {
+        let path = env::current_dir()
+            .context(|| "cannot get the current directory".to_string())?
+            .join(path);
+        let name = path.file_name().unwrap().to_string_lossy();
+        let staging = path.with_file_name(format!(".{name}.staging"));
+        for stale in [&staging, &path] {
+            if stale.is_dir() {
+                fs::remove_dir_all(stale)
+            } else if stale.exists() {
+                fs::remove_file(stale)
+            } else {
+                Ok(())
+            }
+            .context(|| format!("cannot remove {}", stale.display()))?;
+        }
+
+        let include_dir = staging.join("include").join("luajit-2.1");
+        let lib_dir = staging.join("lib");
+        let pkgconfig_dir = lib_dir.join("pkgconfig");
+        let jit_dir = staging.join("share").join("luajit-2.1").join("jit");
+        for dir in [&include_dir, &pkgconfig_dir, &jit_dir] {
+            fs::create_dir_all(dir).context(|| format!("cannot create {}", dir.display()))?;
+        }
+        cp_r(&self.include_dir, &include_dir)?;
+        // Skip the link maps, version scripts and such next to the libraries
+        let entries = fs::read_dir(&self.lib_dir)
+            .context(|| format!("cannot read {}", self.lib_dir.display()))?;
+        for entry in entries {
+            let entry = entry.context(|| format!("cannot read {}", self.lib_dir.display()))?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let is_lib = self.libs.iter().any(|lib| {
+                file_name.starts_with(&format!("lib{}.", lib.name))
+                    || file_name.starts_with(&format!("{}.", lib.name))
+            });
+            if is_lib && entry.path().is_file() {
+                let dst = lib_dir.join(&file_name);
+                copy_file(&entry.path(), &dst).context(|| format!("cannot copy {file_name}"))?;
+            }
+        }
+        if let Some(dir) = self.jit_dir.as_ref().filter(|dir| dir.is_dir()) {
+            cp_r(dir, &jit_dir)?;
+        }
+
+        let pc = pkgconfig_dir.join("luajit.pc");
+        fs::write(&pc, self.pkg_config_file())
+            .context(|| format!("cannot write {}", pc.display()))?;
+
+        let mut command = match format {
+            ArchiveFormat::TarGz => {
+                let mut tar = Command::new("tar");
+                tar.arg("-czf").arg(&path).arg(".");
+                tar
+            }
+            ArchiveFormat::Zip if self.build_info.host.contains("windows") => {
+                // bsdtar, which Windows ships as `tar`, can also write zip files
+                let mut tar = Command::new("tar");
+                tar.args(["--format", "zip", "-cf"]).arg(&path).arg(".");
+                tar
+            }
+            ArchiveFormat::Zip => {
+                let mut zip = Command::new("zip");
+                zip.arg("-qr").arg(&path).arg(".");
+                zip
+            }
+        };
+        command.current_dir(&staging);
+        let status = command.status().ok();
+        if !matches!(status, Some(status) if status.success()) {
+            return Err(Error::CommandFailed {
+                desc: "packaging LuaJIT".to_string(),
+                command: format!("{command:?}"),
+                status,
+            });
+        }
+        fs::remove_dir_all(&staging).context(|| format!("cannot remove {}", staging.display()))?;
+        Ok(path)
+    }
+
+    // pkg-config file for the prefix layout of `write_archive`
+    fn pkg_config_file(&self) -> String {
+        let luajit_h = fs::read_to_string(self.include_dir.join("luajit.h")).unwrap_or_default();
+        let version = luajit_h
+            .lines()
+            .find_map(|line| line.strip_prefix("#define LUAJIT_VERSION"))
+            .and_then(|value| value.trim().trim_matches('"').strip_prefix("LuaJIT "))
+            .unwrap_or(LUAJIT_VERSION);
+        let libs = self
+            .libs
+            .iter()
+            .map(|lib| format!(" -l{}", lib.name))
+            .collect::<String>();
+        let mut private_libs = self
+            .system_libs
+            .iter()
+            .map(|lib| format!(" -l{lib}"))
+            .collect::<String>();
+        if self.target.contains("linux") {
+            private_libs.push_str(" -lm -ldl");
+        }
+        format!(
+            "prefix=${{pcfiledir}}/../..\n\
+             libdir=${{prefix}}/lib\n\
+             includedir=${{prefix}}/include/luajit-2.1\n\
+             \n\
+             Name: LuaJIT\n\
+             Description: Just-in-time compiler for Lua\n\
+             URL: https://luajit.org\n\
+             Version: {version}\n\
+             Libs: -L${{libdir}}{libs}\n\
+             Libs.private:{private_libs}\n\
+             Cflags: -I${{includedir}}\n"
+        )
+    }
+
+    /// Returns the shared library that must be available at runtime, if LuaJIT
+    /// was built with [`Build::shared`].
+    pub fn shared_lib(&self) -> Option<&Path> {
+        self.shared_lib.as_deref()
+    }
+
+    /// Returns the `compile_commands.json` written by [`Build::emit_compile_commands`].
+    pub fn compile_commands(&self) -> Option<&Path> {
+        self.compile_commands.as_deref()
+    }
+
+    /// Returns whether this is a system LuaJIT found by [`Build::resolve`].
+    pub fn is_system(&self) -> bool {
+        self.system
+    }
+
+    /// Returns the versioned glibc symbols (`name@GLIBC_x.y`) the build
+    /// references, from the shared library or, for static builds, the `luajit`
+    /// executable linked alongside it. Empty for non-glibc targets or if
+    /// `objdump` is missing.
+    pub fn glibc_symbols(&self) -> &[String] {
+        &self.glibc_symbols
+    }
+
+    /// Returns the advisory messages about the build configuration, e.g. about
+    /// discouraged options, which were also printed as `cargo:warning`s.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns the build phase durations measured with [`Build::report_timings`].
+    pub fn timings(&self) -> Option<&Timings> {
+        self.timings.as_ref()
+    }
+
+    /// Returns the version script the shared library was linked with, see
+    /// [`Build::version_script`].
+    pub fn version_script(&self) -> Option<&Path> {
+        self.version_script.as_deref()
+    }
+
+    /// Returns the linker map written by [`Build::emit_link_map`].
+    pub fn link_map(&self) -> Option<&Path> {
+        self.link_map.as_deref()
+    }
+
+    /// Returns the directory holding the objects of the static library, see
+    /// [`Build::keep_objects`].
+    pub fn object_dir(&self) -> Option<&Path> {
+        self.object_dir.as_deref()
+    }
+
+    /// Returns whether the build was restored from [`Build::cache_dir`] instead
+    /// of running `make`.
+    pub fn cache_hit(&self) -> bool {
+        self.cache_hit
+    }
+
+    /// Returns what the interpreter printed for [`Build::self_check`], e.g.
+    /// `LuaJIT 2.1.1713484068`. `None` if the check did not run.
+    pub fn self_check(&self) -> Option<&str> {
+        self.self_check.as_deref()
+    }
+
+    /// Returns the relocatable object produced by [`Build::combined_object`].
+    pub fn combined_object(&self) -> Option<&Path> {
+        self.combined_object.as_deref()
+    }
+
+    /// Returns the `luajit` interpreter, if it was installed by [`Build::build_tests`].
+    pub fn executable(&self) -> Option<&Path> {
+        self.executable.as_deref()
+    }
+
+    /// Returns a command running upstream's `test.lua` with the built interpreter.
+    ///
+    /// `None` if tests were not requested or the vendored sources have no test suite.
+    pub fn test_runner(&self) -> Option<Command> {
+        let (executable, test_dir) = (self.executable.as_ref()?, self.test_dir.as_ref()?);
+        let mut command = Command::new(executable);
+        command.current_dir(test_dir).arg("test.lua");
+        Some(command)
+    }
+
+    /// Writes a single header amalgamating all public LuaJIT headers to `path`.
+    ///
+    /// Internal `#include "lua.h"`-style references are dropped since the
+    /// referenced header is already inlined above. Returns the written path.
+    pub fn write_combined_header<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        let mut out = String::new();
+        out.push_str("/* Combined LuaJIT public API header. Generated by luajit-src. */\n");
+        out.push_str("#ifndef LUAJIT_ALL_H\n#define LUAJIT_ALL_H\n");
+        for header in PUBLIC_HEADERS {
+            let header_path = self.include_dir.join(header);
+            let contents = fs::read_to_string(&header_path)
+                .context(|| format!("cannot read {}", header_path.display()))?;
+            out.push_str(&format!("\n/* {header} */\n"));
+            for line in contents.lines() {
+                let is_internal_include = line
+                    .trim_start()
+                    .strip_prefix('#')
+                    .map(|l| l.trim_start())
+                    .and_then(|l| l.strip_prefix("include"))
+                    .map(|l| l.trim().trim_matches('"'))
+                    .is_some_and(|name| PUBLIC_HEADERS.contains(&name));
+                if !is_internal_include {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+        out.push_str("\n#endif\n");
+        fs::write(path, out).context(|| format!("cannot write {}", path.display()))?;
+        Ok(path.to_path_buf())
+    }
+
+    pub fn print_cargo_metadata(&self) {
+        for directive in self.metadata_directives(self.whole_archive) {
+            println!("{directive}");
+        }
+    }
+
+    /// Like [`Artifacts::print_cargo_metadata`], but links the static library the
+    /// way the given downstream crate type needs, ignoring [`Build::whole_archive`].
+    ///
+    /// A `cdylib` or `dylib` gets `+whole-archive` so the whole Lua C API is kept
+    /// for C modules loaded at runtime, other crate types link it plainly. A build
+    /// script knows its own crate type:
+    ///
+    /// ```no_run
+    /// let artifacts = luajit_src::Build::new().build();
+    /// artifacts.print_cargo_metadata_for(luajit_src::CrateType::Cdylib);
+    /// ```
+    pub fn print_cargo_metadata_for(&self, crate_type: CrateType) {
+        for directive in self.metadata_directives_for(crate_type) {
+            println!("{directive}");
+        }
+    }
+
+    fn metadata_directives_for(&self, crate_type: CrateType) -> Vec<String> {
+        self.metadata_directives(matches!(crate_type, CrateType::Cdylib | CrateType::Dylib))
+    }
+
+    // The `cargo:` lines printed by the `print_cargo_metadata*` functions
+    fn metadata_directives(&self, whole_archive: bool) -> Vec<String> {
+        let mut directives = [
+            "HOST_CC",
+            "STATIC_CC",
+            "TARGET_LD",
+            "TARGET_AR",
+            "TARGET_STRIP",
+            "TARGET_XLIBS",
+            "AR",
+            "RANLIB",
+            "STRIP",
+            "MACOSX_DEPLOYMENT_TARGET",
+            "IPHONEOS_DEPLOYMENT_TARGET",
+            "TVOS_DEPLOYMENT_TARGET",
+            "WATCHOS_DEPLOYMENT_TARGET",
+            "XROS_DEPLOYMENT_TARGET",
+        ]
+        .iter()
+        .map(|var| format!("cargo:rerun-if-env-changed={var}"))
+        .collect::<Vec<_>>();
+
+        let lib_dir = self.lib_dir.display();
+        directives.push(format!("cargo:rustc-link-search=native={lib_dir}"));
+        for dir in &self.link_search {
+            directives.push(format!("cargo:rustc-link-search=native={}", dir.display()));
+        }
+        for lib in &self.libs {
+            let kind = match lib.kind {
+                LinkKind::Dylib => "dylib",
+                LinkKind::Static if whole_archive => "static:+whole-archive",
+                LinkKind::Static => "static",
+            };
+            directives.push(format!("cargo:rustc-link-lib={kind}={}", lib.name));
+        }
+        // An rpath from `Build::rpath` already tells the loader where to look
+        let has_rpath = self
+            .link_args
+            .iter()
+            .any(|arg| arg.starts_with("-Wl,-rpath,"));
+        if let Some(shared_lib) = self.shared_lib.as_ref().filter(|_| !has_rpath) {
+            let name = shared_lib.file_name().unwrap().to_string_lossy();
+            let search_path = if name.ends_with(".dll") {
+                "PATH"
+            } else {
+                "library path"
+            };
+            directives.push(format!(
+                "cargo:warning={name} must be on the {search_path} at runtime"
+            ));
+        }
+        for lib in &self.system_libs {
+            directives.push(format!("cargo:rustc-link-lib={lib}"));
+        }
+        for arg in &self.link_args {
+            directives.push(format!("cargo:rustc-link-arg={arg}"));
+        }
+        directives.push(format!("cargo:include={}", self.include_dir.display()));
+        directives.push(format!("cargo:lib={lib_dir}"));
+        directives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_artifacts() -> Artifacts {
+        let target = "x86_64-unknown-linux-gnu".to_string();
+        Artifacts {
+            target: target.clone(),
+            include_dir: PathBuf::from("/luajit/include"),
+            lib_dir: PathBuf::from("/luajit/lib"),
+            libs: vec![Lib::new("luajit", LinkKind::Static)],
+            executable: None,
+            test_dir: None,
+            link_args: Vec::new(),
+            system_libs: Vec::new(),
+            shared_lib: None,
+            build_info: BuildInfo {
+                target: target.clone(),
+                host: target,
+                compiler: "cc".to_string(),
+                compiler_family: CompilerFamily::Gnu,
+                compiler_version: None,
+                compiler_args: String::new(),
+                ar: None,
+                strip: None,
+                cflags: Vec::new(),
+            },
+            host_tools: None,
+            compile_commands: None,
+            whole_archive: false,
+            combined_object: None,
+            system: false,
+            timings: None,
+            link_map: None,
+            version_script: None,
+            object_dir: None,
+            jit_dir: None,
+            link_search: Vec::new(),
+            warnings: Vec::new(),
+            glibc_symbols: Vec::new(),
+            self_check: None,
+            cache_hit: false,
+        }
+    }
+
+    #[test]
+    fn jobs_count() {
+        assert_eq!(Jobs::Serial.count_with(Some("3")), 1);
+        assert_eq!(Jobs::Fixed(6).count_with(Some("3")), 6);
+        assert_eq!(Jobs::Fixed(0).count_with(None), 1);
+        assert_eq!(Jobs::Auto.count_with(Some("3")), 3);
+        assert!(Jobs::Auto.count_with(Some("many")) >= 1);
+        assert!(Jobs::Auto.count_with(None) >= 1);
+    }
+
+    #[test]
+    fn c_string_literal() {
+        assert_eq!(c_string("foo.bar"), r#""foo.bar""#);
+        assert_eq!(c_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(c_string("??="), r#""\?\?=""#);
+        assert_eq!(c_string("é\n1"), r#""\303\251\0121""#);
+    }
+
+    #[test]
+    fn default_out_dir_location() {
+        let target = "x86_64-unknown-linux-gnu";
+        assert_eq!(
+            default_out_dir(Some(PathBuf::from("/work/target")), target),
+            Path::new("/work/target/luajit-build/x86_64-unknown-linux-gnu")
+        );
+        assert_eq!(
+            default_out_dir(None, target),
+            env::temp_dir().join("luajit-build").join(target)
+        );
+    }
+
+    #[test]
+    fn zig_target_triples() {
+        assert_eq!(zig_target("x86_64-unknown-linux-gnu"), "x86_64-linux-gnu");
+        assert_eq!(zig_target("aarch64-apple-darwin"), "aarch64-macos");
+        assert_eq!(zig_target("i686-pc-windows-gnu"), "x86-windows-gnu");
+        assert_eq!(
+            zig_target("armv7-unknown-linux-gnueabihf"),
+            "arm-linux-gnueabihf"
+        );
+        assert_eq!(
+            zig_target("riscv64gc-unknown-linux-musl"),
+            "riscv64-linux-musl"
+        );
+        assert_eq!(zig_target("aarch64-linux-android"), "aarch64-linux-android");
+    }
+
+    #[test]
+    fn target_sys_from_triple() {
+        assert_eq!(target_sys("x86_64-unknown-linux-gnu"), Some("Linux"));
+        assert_eq!(target_sys("aarch64-linux-android"), Some("Linux"));
+        assert_eq!(target_sys("x86_64-pc-windows-gnu"), Some("Windows"));
+        assert_eq!(target_sys("aarch64-apple-darwin"), Some("Darwin"));
+        assert_eq!(target_sys("aarch64-apple-ios"), Some("iOS"));
+        assert_eq!(target_sys("x86_64-unknown-freebsd"), Some("FreeBSD"));
+        assert_eq!(target_sys("x86_64-unknown-illumos"), Some("SunOS"));
+        assert_eq!(target_sys("wasm32-unknown-unknown"), None);
+    }
+
+    #[test]
+    fn min_relver_check() {
+        assert!(check_relver("2.1.1713484068".to_string(), 1713484068).is_ok());
+        assert!(check_relver("2.1.1713484068".to_string(), 1700000000).is_ok());
+        let err = check_relver("2.1.1693350652".to_string(), 1713484068).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::VersionTooOld { found, required: 1713484068 } if found == "2.1.1693350652"
+        ));
+        assert!(check_relver("2.1.0-beta3".to_string(), 1).is_err());
+    }
+
+    #[test]
+    fn metadata_for_crate_type() {
+        let artifacts = test_artifacts();
+        let link_libs = |crate_type| {
+            artifacts
+                .metadata_directives_for(crate_type)
+                .into_iter()
+                .filter(|d| d.starts_with("cargo:rustc-link-lib="))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            link_libs(CrateType::Bin),
+            ["cargo:rustc-link-lib=static=luajit"]
+        );
+        assert_eq!(
+            link_libs(CrateType::Cdylib),
+            ["cargo:rustc-link-lib=static:+whole-archive=luajit"]
+        );
+        let directives = artifacts.metadata_directives_for(CrateType::Bin);
+        assert!(directives.contains(&"cargo:rustc-link-search=native=/luajit/lib".to_string()));
+        assert!(directives.contains(&"cargo:include=/luajit/include".to_string()));
+    }
+
+    #[test]
+    fn gc64_rails() {
+        let validate = |target: &str, width: u8, gc64: bool, expected: bool| {
+            let mut build = Build::new();
+            build.target(target).pointer_width(width).gc64(gc64);
+            if expected {
+                build.require_gc64();
+            } else {
+                build.forbid_gc64();
+            }
+            build.validate().is_ok()
+        };
+        assert!(validate("x86_64-unknown-linux-gnu", 64, true, true));
+        assert!(!validate("x86_64-unknown-linux-gnu", 64, false, true));
+        assert!(validate("x86_64-unknown-linux-gnu", 64, false, false));
+        assert!(!validate("aarch64-unknown-linux-gnu", 64, false, false));
+        assert!(!validate("i686-unknown-linux-gnu", 32, true, true));
+        assert!(validate("i686-unknown-linux-gnu", 32, true, false));
+    }
+
+    #[test]
+    fn metadata_mixed_link_kinds() {
+        let mut artifacts = test_artifacts();
+        artifacts.libs.push(Lib::new("m", LinkKind::Dylib));
+        let link_libs = artifacts
+            .metadata_directives_for(CrateType::Cdylib)
+            .into_iter()
+            .filter(|d| d.starts_with("cargo:rustc-link-lib="))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            link_libs,
+            [
+                "cargo:rustc-link-lib=static:+whole-archive=luajit",
+                "cargo:rustc-link-lib=dylib=m",
+            ]
+        );
+    }
+
+    #[test]
+    fn capability_checks() {
+        let mut build = Build::new();
+        build
+            .target("aarch64-apple-ios")
+            .pointer_width(64)
+            .require_jit();
+        assert!(build.validate().is_err());
+        build.target("aarch64-apple-darwin");
+        assert!(build.validate().is_ok());
+
+        let mut build = Build::new();
+        build.target("x86_64-unknown-linux-gnu").pointer_width(64);
+        build.require_ffi().sandbox(true);
+        assert!(build.validate().is_err());
+    }
+
+    #[test]
+    fn metadata_link_search() {
+        let mut artifacts = test_artifacts();
+        artifacts
+            .link_search
+            .push(PathBuf::from("/opt/jemalloc/lib"));
+        let link_search = artifacts
+            .metadata_directives(false)
+            .into_iter()
+            .filter(|d| d.starts_with("cargo:rustc-link-search="))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            link_search,
+            [
+                "cargo:rustc-link-search=native=/luajit/lib",
+                "cargo:rustc-link-search=native=/opt/jemalloc/lib",
+            ]
+        );
+    }
+
+    #[test]
+    fn glibc_version_order() {
+        assert_eq!(version_key("2.2.5"), [2, 2, 5]);
+        assert!(version_key("2.34") > version_key("2.4"));
+        assert!(version_key("2.28") > version_key("2.27.1"));
+        assert!(version_key("2.3") < version_key("2.3.4"));
     }
 }
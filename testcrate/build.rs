@@ -1,7 +1,179 @@
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    let version = luajit_src::luajit_version();
+    println!("cargo:rustc-env=LUAJIT_SRC_VERSION={version}");
     let mut builder = luajit_src::Build::new();
     builder.lua52compat(cfg!(feature = "lua52compat"));
+    if cfg!(feature = "embed_module") {
+        builder.embed_lua_module("embedded", "src/embedded.lua");
+    }
+    if cfg!(feature = "sandbox") {
+        builder.sandbox(true);
+    }
+    if cfg!(feature = "disable_debug_lib") {
+        builder.disable_debug_lib(true);
+    }
+    if cfg!(feature = "gc64") {
+        builder.gc64(true);
+    }
+    if cfg!(feature = "disable_gc64") {
+        builder.gc64(false);
+    }
+    if cfg!(feature = "relver_file") {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        builder
+            .source_dir(format!("{manifest_dir}/../luajit2"))
+            .relver_file(format!("{manifest_dir}/src/relver.txt"));
+    }
+    if cfg!(feature = "ar_env") {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        let log = format!("{}/ar-wrapper.log", std::env::var("OUT_DIR").unwrap());
+        let _ = std::fs::remove_file(&log);
+        std::env::set_var("AR_WRAPPER_LOG", log);
+        std::env::set_var("AR", format!("{manifest_dir}/src/ar-wrapper.sh"));
+    }
+    if cfg!(feature = "hermetic") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        builder
+            .hermetic(true)
+            .write_build_script(format!("{out_dir}/luajit-hermetic.sh"));
+    }
+    if cfg!(feature = "thin_archive") {
+        builder.thin_archive(true);
+    }
+    if cfg!(feature = "keep_objects") {
+        builder.keep_objects(true);
+    }
+    if cfg!(feature = "build_tests") {
+        builder.build_tests(true);
+    }
+    if cfg!(feature = "pie") {
+        builder.pie(true);
+    }
+    if cfg!(feature = "self_check") {
+        builder.self_check(true);
+    }
+    if cfg!(feature = "fast_math") {
+        builder.fast_math(true);
+    }
+    if cfg!(feature = "shared") {
+        builder.shared(true);
+    }
+    if cfg!(feature = "rpath") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        builder
+            .rpath("$ORIGIN")
+            .rpath(&format!("{out_dir}/luajit-build/lib"));
+    }
+    if cfg!(feature = "build_script") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        builder
+            .jobs(luajit_src::Jobs::Fixed(3))
+            .write_build_script(format!("{out_dir}/luajit-build.sh"));
+    }
+    if cfg!(feature = "cache_dir") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        builder.cache_dir(format!("{out_dir}/luajit-cache"));
+    }
+    if cfg!(feature = "disable_ffi_callbacks") {
+        builder.disable_ffi_callbacks(true);
+    }
+    if cfg!(feature = "default_jit_opt") {
+        builder.default_jit_opt(1);
+    }
+    if cfg!(feature = "patch") {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        builder.patch(format!("{manifest_dir}/src/ffi-callbacks.patch"));
+    }
+    if cfg!(feature = "gc_tuning") {
+        builder.gc_pause(150).gc_step_mul(300);
+    }
+    if cfg!(feature = "patch_dir") {
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        builder.patch_dir(format!("{manifest_dir}/src/patches"));
+    }
     let artifacts = builder.build();
+    if cfg!(feature = "self_check") {
+        let version = artifacts.self_check().unwrap();
+        println!("cargo:rustc-env=LUAJIT_SELF_CHECK={version}");
+    }
+    if cfg!(feature = "build_tests") {
+        let executable = artifacts.executable().unwrap();
+        println!("cargo:rustc-env=LUAJIT_EXECUTABLE={}", executable.display());
+        // Upstream's test suite is only run if the sources vendor it
+        if let Some(mut test_runner) = artifacts.test_runner() {
+            assert!(test_runner.status().unwrap().success());
+        }
+    }
+    if cfg!(feature = "disable_gc64") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let warnings = artifacts.warnings().join("\n");
+        std::fs::write(format!("{out_dir}/warnings"), warnings).unwrap();
+    }
+    if cfg!(feature = "combined_header") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let path = format!("{out_dir}/luajit_all.h");
+        artifacts.write_combined_header(path).unwrap();
+    }
+    if cfg!(feature = "import_header") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let path = format!("{out_dir}/luajit_import.h");
+        artifacts.write_import_header(path).unwrap();
+        let include_dir = artifacts.include_dir().display();
+        println!("cargo:rustc-env=LUAJIT_INCLUDE_DIR={include_dir}");
+    }
+    if cfg!(feature = "write_archive") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let path = format!("{out_dir}/luajit.tar.gz");
+        let format = luajit_src::ArchiveFormat::TarGz;
+        artifacts.write_archive(path.as_ref(), format).unwrap();
+    }
+    if cfg!(feature = "write_symbols") {
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let path = format!("{out_dir}/luajit.symbols");
+        artifacts.write_symbols(path).unwrap();
+    }
+    if cfg!(feature = "merge") {
+        // On macOS the other build is for the other architecture, elsewhere an
+        // identical build has to merge into the same library
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let target = std::env::var("TARGET").unwrap();
+        let other_target = match &*target {
+            "aarch64-apple-darwin" => "x86_64-apple-darwin",
+            "x86_64-apple-darwin" => "aarch64-apple-darwin",
+            target => target,
+        };
+        let other = builder
+            .target(other_target)
+            .out_dir(format!("{out_dir}/luajit-{other_target}"))
+            .build();
+        let merged = artifacts.merge(&[other]).unwrap();
+        let lib = merged.lib_dir().join("libluajit-5.1.a");
+        println!("cargo:rustc-env=LUAJIT_MERGED_LIB={}", lib.display());
+    }
+    if cfg!(feature = "resolve") {
+        // A fake pkg-config standing in for a system LuaJIT
+        let manifest_dir = env!("CARGO_MANIFEST_DIR");
+        std::env::set_var("PKG_CONFIG", format!("{manifest_dir}/src/pkg-config.sh"));
+        let mut lib_dirs = Vec::new();
+        for use_pkg_config in ["0", "1"] {
+            std::env::set_var("LUAJIT_SYS_USE_PKG_CONFIG", use_pkg_config);
+            let resolved = builder.resolve().unwrap();
+            lib_dirs.push(resolved.lib_dir().display().to_string());
+        }
+        std::env::remove_var("LUAJIT_SYS_USE_PKG_CONFIG");
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        std::fs::write(format!("{out_dir}/resolved"), lib_dirs.join("\n")).unwrap();
+    }
+    if cfg!(feature = "cache_dir") {
+        // The same configuration built elsewhere must come from the cache
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let cached = builder.out_dir(format!("{out_dir}/luajit-cached")).build();
+        std::fs::write(
+            format!("{out_dir}/cache-hit"),
+            cached.cache_hit().to_string(),
+        )
+        .unwrap();
+    }
     artifacts.print_cargo_metadata();
 }
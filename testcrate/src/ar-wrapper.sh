@@ -0,0 +1,4 @@
+#!/bin/sh
+# Records how it was invoked, then hands over to the real ar
+echo "$@" >>"$AR_WRAPPER_LOG"
+exec ar "$@"
@@ -0,0 +1,7 @@
+local M = {}
+
+function M.hello()
+    return "hello from embedded"
+end
+
+return M
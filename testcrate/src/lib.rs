@@ -3,6 +3,7 @@ use std::os::raw::{c_char, c_int, c_long, c_void};
 extern "C" {
     pub fn luaL_newstate() -> *mut c_void;
     pub fn luaL_openlibs(state: *mut c_void);
+    pub fn lua_close(state: *mut c_void);
     pub fn lua_getfield(state: *mut c_void, index: c_int, k: *const c_char);
     pub fn lua_tolstring(state: *mut c_void, index: c_int, len: *mut c_long) -> *const c_char;
     pub fn luaL_loadstring(state: *mut c_void, s: *const c_char) -> c_int;
@@ -13,6 +14,45 @@ pub unsafe fn lua_getglobal(state: *mut c_void, k: *const c_char) {
     lua_getfield(state, -10002 /* LUA_GLOBALSINDEX */, k);
 }
 
+// Runs `code` in a new state with the standard libraries opened and returns the
+// string it returns
+pub unsafe fn eval(code: &str) -> Vec<u8> {
+    use std::{ffi::CString, slice};
+    let state = luaL_newstate();
+    assert!(!state.is_null());
+
+    luaL_openlibs(state);
+
+    let code = CString::new(code).unwrap();
+    assert_eq!(0, luaL_loadstring(state, code.as_ptr()));
+    assert_eq!(0, lua_pcall(state, 0, 1, 0));
+
+    let mut len: c_long = 0;
+    let result_ptr = lua_tolstring(state, -1, &mut len);
+    assert!(!result_ptr.is_null());
+    let result = slice::from_raw_parts(result_ptr as *const u8, len as usize).to_vec();
+    lua_close(state);
+    result
+}
+
+// Compiles `source` as C with warnings as errors, searching `include_dirs`
+pub fn compile_c(source: &str, include_dirs: &[&str]) -> bool {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    let mut cc = Command::new("cc")
+        .args(["-Wall", "-Werror", "-x", "c", "-c", "-", "-o", "/dev/null"])
+        .args(include_dirs.iter().map(|dir| format!("-I{dir}")))
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    cc.stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    cc.wait().unwrap().success()
+}
+
 #[test]
 fn test_lua() {
     use std::{ptr, slice};
@@ -33,40 +73,373 @@ fn test_lua() {
     }
 }
 
+// `luajit_src::luajit_version()` must agree with the built VM
+#[cfg(not(feature = "relver_file"))]
+#[test]
+fn test_luajit_version() {
+    let expected = concat!("LuaJIT ", env!("LUAJIT_SRC_VERSION"));
+    assert!(expected.starts_with("LuaJIT 2.1."), "{expected}");
+    assert_eq!(unsafe { eval("return jit.version") }, expected.as_bytes());
+}
+
 #[test]
 fn test_lua52compat() {
-    use std::{ptr, slice};
-    unsafe {
-        let state = luaL_newstate();
-        assert!(state != ptr::null_mut());
+    let code = r#"
+        local lua52compat = "no"
+        local t = setmetatable({}, {
+            __pairs = function(t)
+                lua52compat = "yes"
+                return next, t, nil
+            end
+        })
+        for k,v in pairs(t) do end
+        return lua52compat
+    "#;
+    let lua52compat = unsafe { eval(code) };
 
-        luaL_openlibs(state);
+    #[cfg(feature = "lua52compat")]
+    assert_eq!(lua52compat, b"yes");
+    #[cfg(not(feature = "lua52compat"))]
+    assert_eq!(lua52compat, b"no");
+}
 
-        let code = "
-            lua52compat = \"no\"
-            t = setmetatable({}, {
-                __pairs = function(t)
-                    lua52compat = \"yes\"
-                    return next, t, nil
-                end
-            })
-            for k,v in pairs(t) do end
-        \0";
-        let ret1 = luaL_loadstring(state, code.as_ptr().cast());
-        assert_eq!(0, ret1);
-        let ret2 = lua_pcall(state, 0, 0, 0);
-        assert_eq!(0, ret2);
-
-        let lua52compat = {
-            lua_getglobal(state, "lua52compat\0".as_ptr().cast());
-            let mut len: c_long = 0;
-            let lua52compat_ptr = lua_tolstring(state, -1, &mut len);
-            slice::from_raw_parts(lua52compat_ptr as *const u8, len as usize)
-        };
+#[cfg(feature = "embed_module")]
+#[test]
+fn test_embed_module() {
+    let embedded = unsafe { eval(r#"return require("embedded").hello()"#) };
+    assert_eq!(embedded, b"hello from embedded");
+}
+
+#[cfg(feature = "sandbox")]
+#[test]
+fn test_sandbox() {
+    let code = r#"
+        return string.format("%s %s %s %d",
+            type(os), type(io), tostring(pcall(require, "ffi")), 6 * 7)
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"nil nil false 42");
+}
 
-        #[cfg(feature = "lua52compat")]
-        assert_eq!(lua52compat, b"yes");
-        #[cfg(not(feature = "lua52compat"))]
-        assert_eq!(lua52compat, b"no");
+#[cfg(feature = "disable_debug_lib")]
+#[test]
+fn test_disable_debug_lib() {
+    let code = r#"
+        local ok, err = pcall(function() error("boom") end)
+        return string.format("%s %s %s",
+            type(debug), tostring(ok), tostring(err:match(":%d+: boom$") ~= nil))
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"nil false true");
+}
+
+#[cfg(feature = "gc64")]
+#[test]
+fn test_gc64() {
+    let gc64 = unsafe { eval(r#"return tostring(require("ffi").abi("gc64"))"#) };
+    assert_eq!(gc64, b"true");
+}
+
+#[cfg(feature = "disable_gc64")]
+#[test]
+fn test_disable_gc64() {
+    let gc64 = unsafe { eval(r#"return tostring(require("ffi").abi("gc64"))"#) };
+    assert_eq!(gc64, b"false");
+}
+
+#[cfg(feature = "disable_gc64")]
+#[test]
+fn test_disable_gc64_warning() {
+    let warnings = include_str!(concat!(env!("OUT_DIR"), "/warnings"));
+    assert!(
+        warnings.lines().any(|w| w.starts_with("disabling GC64")),
+        "{warnings}"
+    );
+}
+
+#[cfg(feature = "relver_file")]
+#[test]
+fn test_relver_file() {
+    let relver = unsafe { eval("return jit.version") };
+    assert_eq!(relver, b"LuaJIT 2.1.1234567890");
+}
+
+#[cfg(feature = "resolve")]
+#[test]
+fn test_resolve() {
+    // Vendored without `LUAJIT_SYS_USE_PKG_CONFIG=1`, the fake system LuaJIT with it
+    let resolved = include_str!(concat!(env!("OUT_DIR"), "/resolved"));
+    let lib_dirs = resolved.lines().collect::<Vec<_>>();
+    let vendored = concat!(env!("OUT_DIR"), "/luajit-build/lib");
+    assert_eq!(lib_dirs, [vendored, "/opt/luajit/lib"]);
+}
+
+#[cfg(feature = "ar_env")]
+#[test]
+fn test_ar_env() {
+    let log = include_str!(concat!(env!("OUT_DIR"), "/ar-wrapper.log"));
+    assert!(
+        log.lines().any(|line| line.contains("libluajit.a")),
+        "{log}"
+    );
+}
+
+#[cfg(feature = "hermetic")]
+#[test]
+fn test_hermetic() {
+    // make must have run, and succeeded, inside a network namespace
+    let script = include_str!(concat!(env!("OUT_DIR"), "/luajit-hermetic.sh"));
+    assert!(script.contains("\n'unshare' '--net' '--map-root-user' 'make' '-e'"));
+}
+
+#[cfg(feature = "thin_archive")]
+#[test]
+fn test_thin_archive() {
+    // This test binary linked against it, so the referenced objects were found
+    test_lua();
+    let archive = concat!(env!("OUT_DIR"), "/luajit-build/lib/libluajit-5.1.a");
+    let contents = std::fs::read(archive).unwrap();
+    assert!(contents.starts_with(b"!<thin>\n"));
+}
+
+#[cfg(feature = "merge")]
+#[test]
+fn test_merge() {
+    let merged = env!("LUAJIT_MERGED_LIB");
+    if cfg!(target_os = "macos") {
+        let output = std::process::Command::new("lipo")
+            .arg("-archs")
+            .arg(merged)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let archs = String::from_utf8(output.stdout).unwrap();
+        let mut archs = archs.split_whitespace().collect::<Vec<_>>();
+        archs.sort();
+        assert_eq!(archs, ["arm64", "x86_64"]);
+    } else {
+        let lib = concat!(env!("OUT_DIR"), "/luajit-build/lib/libluajit-5.1.a");
+        assert_eq!(std::fs::read(merged).unwrap(), std::fs::read(lib).unwrap());
     }
 }
+
+#[cfg(feature = "fast_math")]
+#[test]
+fn test_fast_math() {
+    let code = r#"
+        return string.format("%.1f %d %s",
+            1.5 * 4 + 0.5, math.floor(7 / 2), tostring(math.sqrt(16)))
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"6.5 3 4");
+}
+
+#[cfg(feature = "shared")]
+#[test]
+fn test_shared() {
+    test_lua();
+    // Loaded under its soname, which must be installed next to the library
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let soname = concat!(env!("OUT_DIR"), "/luajit-build/lib/libluajit-5.1.so.2");
+    assert!(maps.lines().any(|line| line.ends_with(soname)));
+}
+
+#[cfg(feature = "rpath")]
+#[test]
+fn test_rpath() {
+    // Without the library path Cargo sets up for tests only the rpath finds LuaJIT
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_lua"])
+        .env_remove("LD_LIBRARY_PATH")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stdout}{stderr}");
+    assert!(stdout.contains("1 passed"), "{stdout}");
+}
+
+#[cfg(feature = "build_script")]
+#[test]
+fn test_build_script() {
+    let script = include_str!(concat!(env!("OUT_DIR"), "/luajit-build.sh"));
+    assert!(script.starts_with("#!/bin/sh\n"));
+    assert!(script.contains("export STATIC_CC='"));
+    assert!(script.contains("export TARGET_SYS='Linux'"));
+    assert!(script.contains("\n'make' '-e' '-j3'"));
+}
+
+#[cfg(feature = "keep_objects")]
+#[test]
+fn test_keep_objects() {
+    let object_dir = concat!(env!("OUT_DIR"), "/luajit-build/objects");
+    let objects = std::fs::read_dir(object_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    for object in ["lj_vm.o", "lj_state.o", "lib_base.o"] {
+        assert!(objects.iter().any(|o| o == object), "{object} missing");
+    }
+}
+
+#[cfg(feature = "combined_header")]
+#[test]
+fn test_combined_header() {
+    let probe = "
+        #include \"luajit_all.h\"
+        int main(void) {
+            lua_State *L = luaL_newstate();
+            luaL_openlibs(L);
+            luaJIT_setmode(L, 0, LUAJIT_MODE_ENGINE | LUAJIT_MODE_ON);
+            lua_close(L);
+            return 0;
+        }
+    ";
+    assert!(compile_c(probe, &[env!("OUT_DIR")]));
+}
+
+#[cfg(feature = "import_header")]
+#[test]
+fn test_import_header() {
+    let probe = "
+        #include \"luajit_import.h\"
+        int main(void) {
+            lua_State *L = luaL_newstate();
+            luaL_openlibs(L);
+            lua_close(L);
+            return 0;
+        }
+    ";
+    let include_dirs = [env!("OUT_DIR"), env!("LUAJIT_INCLUDE_DIR")];
+    assert!(compile_c(probe, &include_dirs));
+}
+
+#[cfg(feature = "self_check")]
+#[test]
+fn test_self_check() {
+    // The build fails if the interpreter does not run, so this is what it printed
+    let version = env!("LUAJIT_SELF_CHECK");
+    assert!(version.starts_with("LuaJIT 2."), "{version}");
+}
+
+#[cfg(feature = "build_tests")]
+#[test]
+fn test_build_tests() {
+    let output = std::process::Command::new(env!("LUAJIT_EXECUTABLE"))
+        .args(["-e", "io.write(jit.version, \" \", 6 * 7)"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("LuaJIT 2."), "{stdout}");
+    assert!(stdout.ends_with(" 42"), "{stdout}");
+}
+
+#[cfg(feature = "pie")]
+#[test]
+fn test_pie() {
+    // A position-independent executable is an `ET_DYN` ELF file
+    let elf = std::fs::read(env!("LUAJIT_EXECUTABLE")).unwrap();
+    assert!(elf.starts_with(b"\x7fELF"));
+    assert_eq!(u16::from_le_bytes([elf[16], elf[17]]), 3);
+}
+
+#[cfg(feature = "write_archive")]
+#[test]
+fn test_write_archive() {
+    let archive = concat!(env!("OUT_DIR"), "/luajit.tar.gz");
+    let output = std::process::Command::new("tar")
+        .arg("-tzf")
+        .arg(archive)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let entries = String::from_utf8(output.stdout).unwrap();
+    for entry in [
+        "./include/luajit-2.1/lua.h",
+        "./lib/libluajit-5.1.a",
+        "./lib/pkgconfig/luajit.pc",
+        "./share/luajit-2.1/jit/vmdef.lua",
+    ] {
+        assert!(entries.lines().any(|e| e == entry), "{entry} missing");
+    }
+}
+
+#[cfg(feature = "write_symbols")]
+#[test]
+fn test_write_symbols() {
+    let symbols = include_str!(concat!(env!("OUT_DIR"), "/luajit.symbols"));
+    for symbol in [
+        "lua_pcall",
+        "luaL_newstate",
+        "luaJIT_setmode",
+        "luaopen_jit",
+    ] {
+        assert!(symbols.lines().any(|s| s == symbol), "{symbol} missing");
+    }
+    assert!(!symbols.lines().any(|s| s.starts_with("lj_")));
+}
+
+#[cfg(feature = "cache_dir")]
+#[test]
+fn test_cache_dir() {
+    let cache_hit = include_str!(concat!(env!("OUT_DIR"), "/cache-hit"));
+    assert_eq!(cache_hit, "true");
+}
+
+#[cfg(feature = "disable_ffi_callbacks")]
+#[test]
+fn test_disable_ffi_callbacks() {
+    let code = r#"
+        local ffi = require("ffi")
+        local ok = pcall(ffi.cast, "void (*)(void)", function() end)
+        return string.format("%s %d", tostring(ok), ffi.new("struct { int x; }", 42).x)
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"false 42");
+}
+
+#[cfg(feature = "default_jit_opt")]
+#[test]
+fn test_default_jit_opt() {
+    let code = r#"
+        local flags = {}
+        for _, flag in ipairs({ select(2, jit.status()) }) do
+            flags[flag] = true
+        end
+        return string.format("%s %s", tostring(flags.fold == true), tostring(flags.loop == true))
+    "#;
+    // -O1 enables fold/cse/dce but not the loop optimizations
+    assert_eq!(unsafe { eval(code) }, b"true false");
+}
+
+#[cfg(feature = "patch")]
+#[test]
+fn test_patch() {
+    let code = r#"
+        local ok = pcall(require("ffi").cast, "void (*)(void)", function() end)
+        return tostring(ok)
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"false");
+}
+
+#[cfg(feature = "gc_tuning")]
+#[test]
+fn test_gc_tuning() {
+    let code = r#"
+        return string.format("%d %d", collectgarbage("setpause", 200), collectgarbage("setstepmul", 200))
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"150 300");
+}
+
+#[cfg(feature = "patch_dir")]
+#[test]
+fn test_patch_dir() {
+    // `a-reword-comment.patch` only applies on top of `b-disable-callbacks.patch`
+    let source = concat!(env!("OUT_DIR"), "/luajit-build/build/src/lj_ccallback.c");
+    let source = std::fs::read_to_string(source).unwrap();
+    assert!(source.contains("ct = NULL;  /* Callbacks disabled in series order. */"));
+    assert!(!source.contains("/* Callbacks disabled by the series. */"));
+
+    let code = r#"
+        local ok = pcall(require("ffi").cast, "void (*)(void)", function() end)
+        return tostring(ok)
+    "#;
+    assert_eq!(unsafe { eval(code) }, b"false");
+}
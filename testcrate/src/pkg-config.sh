@@ -0,0 +1,9 @@
+#!/bin/sh
+# Stands in for pkg-config, reporting a system LuaJIT under /opt/luajit
+case "$1" in
+    --modversion) echo 2.1.1713484068 ;;
+    --variable=includedir) echo /opt/luajit/include/luajit-2.1 ;;
+    --variable=libdir) echo /opt/luajit/lib ;;
+    --libs-only-l) echo -lluajit-5.1 ;;
+    *) exit 1 ;;
+esac